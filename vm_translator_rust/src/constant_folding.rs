@@ -0,0 +1,278 @@
+//! Constant folding and algebraic simplification over the parsed vm program.
+//!
+//! Values are 16 bits words, arithmetic wraps around like the Hack ALU does and the
+//! comparisons are done on the two's complement interpretation, true being -1 and false 0.
+//! Only commands that are adjacent in the program are combined, so a label in between two
+//! pushes keeps them apart.
+
+use crate::parser::Command;
+
+const TRUE: u16 = 0xFFFF;
+const FALSE: u16 = 0;
+
+fn push_constant(value: u16) -> Command {
+    Command::Push {
+        memory_segment: "constant".to_string(),
+        index: value,
+    }
+}
+
+fn constant(command: &Command) -> Option<u16> {
+    match command {
+        Command::Push {
+            memory_segment,
+            index,
+        } if memory_segment == "constant" => Some(*index),
+        _ => None,
+    }
+}
+
+fn boolean(value: bool) -> u16 {
+    if value {
+        TRUE
+    } else {
+        FALSE
+    }
+}
+
+fn fold_single_operand(instruction: &str, x: u16) -> Option<u16> {
+    match instruction {
+        "neg" => Some(x.wrapping_neg()),
+        "not" => Some(!x),
+        _ => None,
+    }
+}
+
+//x is the deepest operand, y the one on top of the stack
+fn fold_double_operand(instruction: &str, x: u16, y: u16) -> Option<u16> {
+    match instruction {
+        "add" => Some(x.wrapping_add(y)),
+        "sub" => Some(x.wrapping_sub(y)),
+        "and" => Some(x & y),
+        "or" => Some(x | y),
+        "mul" => Some(x.wrapping_mul(y)),
+        //division truncates toward zero, by zero the quotient is 0 and the remainder x
+        "div" if y == 0 => Some(0),
+        "div" => Some((x as i16).wrapping_div(y as i16) as u16),
+        "mod" if y == 0 => Some(x),
+        "mod" => Some((x as i16).wrapping_rem(y as i16) as u16),
+        //shifting by 16 or more shifts every bit out
+        "shl" => Some(x.checked_shl(y as u32).unwrap_or(0)),
+        "shr" => Some(((x as i16) >> y.min(15)) as u16),
+        "ushr" => Some(x.checked_shr(y as u32).unwrap_or(0)),
+        "eq" => Some(boolean(x == y)),
+        "lt" => Some(boolean((x as i16) < (y as i16))),
+        "gt" => Some(boolean((x as i16) > (y as i16))),
+        "ne" => Some(boolean(x != y)),
+        "lte" => Some(boolean((x as i16) <= (y as i16))),
+        "gte" => Some(boolean((x as i16) >= (y as i16))),
+        _ => None,
+    }
+}
+
+//x <instruction> y == x
+fn is_right_identity(instruction: &str, y: u16) -> bool {
+    matches!(
+        (instruction, y),
+        ("add" | "sub" | "or" | "shl" | "shr" | "ushr", 0) | ("and", TRUE) | ("mul" | "div", 1)
+    )
+}
+
+//x <instruction> y == y
+fn is_left_identity(instruction: &str, x: u16) -> bool {
+    matches!(
+        (instruction, x),
+        ("add" | "or", 0) | ("and", TRUE) | ("mul", 1)
+    )
+}
+
+fn simplify(folded: &mut Vec<Command>, instruction: &str) -> bool {
+    let len = folded.len();
+    let top = folded.last().and_then(constant);
+
+    if let Some(x) = top.and_then(|x| fold_single_operand(instruction, x)) {
+        folded[len - 1] = push_constant(x);
+        return true;
+    }
+    if matches!(instruction, "neg" | "not") {
+        //neg neg / not not
+        if let Some(Command::Arithmetic(previous)) = folded.last() {
+            if previous == instruction {
+                folded.pop();
+                return true;
+            }
+        }
+        return false;
+    }
+
+    let below = len.checked_sub(2).and_then(|i| constant(&folded[i]));
+    match (below, top) {
+        (Some(x), Some(y)) => match fold_double_operand(instruction, x, y) {
+            Some(value) => {
+                folded.truncate(len - 2);
+                folded.push(push_constant(value));
+                true
+            }
+            None => false,
+        },
+        (_, Some(y)) if is_right_identity(instruction, y) => {
+            folded.pop();
+            true
+        }
+        (Some(x), None)
+            if is_left_identity(instruction, x)
+                && matches!(folded[len - 1], Command::Push { .. }) =>
+        {
+            folded.remove(len - 2);
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn fold(commands: &mut Vec<Command>) {
+    let mut folded: Vec<Command> = Vec::with_capacity(commands.len());
+
+    for command in commands.drain(..) {
+        if let Command::Arithmetic(instruction) = &command {
+            if simplify(&mut folded, instruction) {
+                continue;
+            }
+        }
+        folded.push(command);
+    }
+
+    *commands = folded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn fold_program(program: &str) -> Vec<Command> {
+        let mut commands = parser::parse_file(&mut program.as_bytes()).unwrap();
+        fold(&mut commands);
+        commands
+    }
+
+    fn parse_program(program: &str) -> Vec<Command> {
+        parser::parse_file(&mut program.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_fold_arithmetic_with_wraparound() {
+        assert_eq!(
+            fold_program("push constant 3\npush constant 4\nadd"),
+            parse_program("push constant 7")
+        );
+        assert_eq!(
+            fold_program("push constant 32767\npush constant 1\nadd"),
+            parse_program("push constant -32768")
+        );
+        assert_eq!(
+            fold_program("push constant 3\npush constant 4\nsub"),
+            parse_program("push constant -1")
+        );
+        assert_eq!(
+            fold_program("push constant 0\nnot"),
+            parse_program("push constant -1")
+        );
+    }
+
+    #[test]
+    fn test_fold_comparison_is_signed() {
+        assert_eq!(
+            fold_program("push constant 32767\npush constant 1\nneg\nlt"),
+            parse_program("push constant 0")
+        );
+        assert_eq!(
+            fold_program("push constant 1\nneg\npush constant 32767\nlt"),
+            parse_program("push constant -1")
+        );
+        assert_eq!(
+            fold_program("push constant 17\npush constant 17\neq\nnot"),
+            parse_program("push constant 0")
+        );
+        assert_eq!(
+            fold_program("push constant 1\nneg\npush constant 32767\ngte"),
+            parse_program("push constant 0")
+        );
+        assert_eq!(
+            fold_program("push constant 17\npush constant 17\nlte"),
+            parse_program("push constant -1")
+        );
+    }
+
+    #[test]
+    fn test_fold_extended_arithmetic() {
+        for (program, expected) in [
+            (
+                "push constant 300\npush constant 300\nmul",
+                "push constant 24464",
+            ),
+            (
+                "push constant 7\nneg\npush constant 2\ndiv",
+                "push constant -3",
+            ),
+            (
+                "push constant 7\nneg\npush constant 2\nmod",
+                "push constant -1",
+            ),
+            ("push constant 7\npush constant 0\ndiv", "push constant 0"),
+            ("push constant 7\npush constant 0\nmod", "push constant 7"),
+            (
+                "push constant 3\npush constant 14\nshl",
+                "push constant -16384",
+            ),
+            (
+                "push constant 4\nneg\npush constant 1\nshr",
+                "push constant -2",
+            ),
+            (
+                "push constant 4\nneg\npush constant 20\nshr",
+                "push constant -1",
+            ),
+            (
+                "push constant 4\nneg\npush constant 14\nushr",
+                "push constant 3",
+            ),
+            ("push constant 4\npush constant 16\nushr", "push constant 0"),
+            ("push local 0\npush constant 1\nmul", "push local 0"),
+            ("push local 0\npush constant 0\nshr", "push local 0"),
+        ] {
+            assert_eq!(
+                fold_program(program),
+                parse_program(expected),
+                "{}",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn test_simplify_identities() {
+        assert_eq!(
+            fold_program("push local 0\npush constant 0\nadd"),
+            parse_program("push local 0")
+        );
+        assert_eq!(
+            fold_program("push constant 0\npush local 0\nor"),
+            parse_program("push local 0")
+        );
+        assert_eq!(
+            fold_program("push local 0\npush constant 0\nnot\nand"),
+            parse_program("push local 0")
+        );
+        assert_eq!(
+            fold_program("push local 0\nneg\nneg\npush constant 0\nsub"),
+            parse_program("push local 0")
+        );
+    }
+
+    #[test]
+    fn test_label_prevents_folding() {
+        let program = "push constant 3\nlabel LOOP\npush constant 4\nadd";
+        assert_eq!(fold_program(program), parse_program(program));
+    }
+}
@@ -0,0 +1,120 @@
+//! Whole program removal of the functions that can't be reached from the entry point.
+//!
+//! The roots of the call graph are the entry function and whatever commands sit
+//! before the first function of a file, since the latter run without being called.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::{Command, VmFile};
+
+//returns the commands a function is made of, keyed by its name
+fn function_bodies(files: &[VmFile]) -> HashMap<&str, &[Command]> {
+    files.iter().flat_map(VmFile::functions).collect()
+}
+
+fn called_functions(commands: &[Command]) -> impl Iterator<Item = &str> {
+    commands.iter().filter_map(|command| match command {
+        Command::Call { name, .. } => Some(name.as_str()),
+        _ => None,
+    })
+}
+
+fn reachable_functions<'a>(files: &'a [VmFile], entry: &'a str) -> HashSet<&'a str> {
+    let bodies = function_bodies(files);
+    let mut to_visit: Vec<&str> = vec![entry];
+    for file in files {
+        let first_function = file
+            .commands
+            .iter()
+            .position(|command| matches!(command, Command::Function { .. }))
+            .unwrap_or(file.commands.len());
+        to_visit.extend(called_functions(&file.commands[..first_function]));
+    }
+
+    let mut reachable = HashSet::new();
+    while let Some(function) = to_visit.pop() {
+        if !reachable.insert(function) {
+            continue;
+        }
+        if let Some(body) = bodies.get(function) {
+            to_visit.extend(called_functions(body));
+        }
+    }
+    reachable
+}
+
+/// Removes the unreachable functions and returns their names, in program order.
+/// Nothing is removed when the entry function isn't part of the program, it is then
+/// most likely a library translated on its own.
+pub fn eliminate(files: &mut [VmFile], entry: &str) -> Vec<String> {
+    let reachable: HashSet<String> = {
+        let bodies = function_bodies(files);
+        if !bodies.contains_key(entry) {
+            return Vec::new();
+        }
+        reachable_functions(files, entry)
+            .into_iter()
+            .map(str::to_string)
+            .collect()
+    };
+
+    let mut dropped = Vec::new();
+    for file in files.iter_mut() {
+        let mut is_dead = false;
+        file.commands.retain(|command| {
+            if let Command::Function { name, .. } = command {
+                is_dead = !reachable.contains(name);
+                if is_dead {
+                    dropped.push(name.clone());
+                }
+            }
+            !is_dead
+        });
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::vm_file;
+
+    #[test]
+    fn test_unreachable_functions_are_dropped() {
+        let mut files = [
+            vm_file(
+                "Main",
+                "function Main.main 0\ncall Math.abs 1\nreturn\n\
+                 function Main.unused 0\ncall Math.max 2\nreturn",
+            ),
+            vm_file(
+                "Math",
+                "function Math.abs 0\ncall Math.abs 1\nreturn\n\
+                 function Math.max 0\nreturn\nfunction Math.min 0\nreturn",
+            ),
+        ];
+        let dropped = eliminate(&mut files, "Main.main");
+        assert_eq!(dropped, ["Main.unused", "Math.max", "Math.min"]);
+        assert_eq!(
+            files[1],
+            vm_file("Math", "function Math.abs 0\ncall Math.abs 1\nreturn")
+        );
+    }
+
+    #[test]
+    fn test_nothing_dropped_without_entry() {
+        let mut files = [vm_file("Math", "function Math.max 0\nreturn")];
+        assert!(eliminate(&mut files, "Sys.init").is_empty());
+        assert_eq!(files[0], vm_file("Math", "function Math.max 0\nreturn"));
+    }
+
+    #[test]
+    fn test_code_outside_functions_is_a_root() {
+        let mut files = [vm_file(
+            "Main",
+            "call Main.used 0\nfunction Sys.init 0\nreturn\n\
+             function Main.used 0\nreturn\nfunction Main.unused 0\nreturn",
+        )];
+        assert_eq!(eliminate(&mut files, "Sys.init"), ["Main.unused"]);
+    }
+}
@@ -0,0 +1,173 @@
+//! Minimal Hack assembler and cpu, used by the tests to run the translated programs.
+
+use std::collections::HashMap;
+
+const RAM_SIZE: usize = 32768;
+const FIRST_VARIABLE_ADDRESS: u16 = 16;
+
+enum Instruction {
+    Address(u16),
+    Compute {
+        dest: String,
+        comp: String,
+        jump: String,
+    },
+}
+
+pub struct Emulator {
+    pub ram: Vec<u16>,
+    rom: Vec<Instruction>,
+    pc: usize,
+}
+
+fn predefined_symbols() -> HashMap<String, u16> {
+    let mut symbols = HashMap::from([
+        ("SP".to_string(), 0),
+        ("LCL".to_string(), 1),
+        ("ARG".to_string(), 2),
+        ("THIS".to_string(), 3),
+        ("THAT".to_string(), 4),
+        ("SCREEN".to_string(), 16384),
+        ("KBD".to_string(), 24576),
+    ]);
+    for register in 0..16 {
+        symbols.insert(format!("R{}", register), register);
+    }
+    symbols
+}
+
+fn compute(comp: &str, d: u16, a: u16, m: u16) -> u16 {
+    let (comp, x) = if comp.contains('M') {
+        (comp.replace('M', "X"), m)
+    } else {
+        (comp.replace('A', "X"), a)
+    };
+    match comp.as_str() {
+        "0" => 0,
+        "1" => 1,
+        "-1" => u16::MAX,
+        "D" => d,
+        "X" => x,
+        "!D" => !d,
+        "!X" => !x,
+        "-D" => d.wrapping_neg(),
+        "-X" => x.wrapping_neg(),
+        "D+1" | "1+D" => d.wrapping_add(1),
+        "X+1" | "1+X" => x.wrapping_add(1),
+        "D-1" => d.wrapping_sub(1),
+        "X-1" => x.wrapping_sub(1),
+        "D+X" | "X+D" => d.wrapping_add(x),
+        "D-X" => d.wrapping_sub(x),
+        "X-D" => x.wrapping_sub(d),
+        "D&X" | "X&D" => d & x,
+        "D|X" | "X|D" => d | x,
+        _ => panic!("invalid computation {}", comp),
+    }
+}
+
+fn jumps(jump: &str, value: u16) -> bool {
+    let value = value as i16;
+    match jump {
+        "" => false,
+        "JGT" => value > 0,
+        "JEQ" => value == 0,
+        "JGE" => value >= 0,
+        "JLT" => value < 0,
+        "JNE" => value != 0,
+        "JLE" => value <= 0,
+        "JMP" => true,
+        _ => panic!("invalid jump {}", jump),
+    }
+}
+
+impl Emulator {
+    pub fn new(instructions: &[String]) -> Self {
+        let mut symbols = predefined_symbols();
+        let mut address = 0;
+        for instruction in instructions {
+            if let Some(label) = instruction.strip_prefix('(') {
+                let label = label.trim_end_matches(')').to_string();
+                assert!(
+                    symbols.insert(label.clone(), address).is_none(),
+                    "duplicated label {}",
+                    label
+                );
+            } else {
+                address += 1;
+            }
+        }
+
+        let mut next_variable = FIRST_VARIABLE_ADDRESS;
+        let rom = instructions
+            .iter()
+            .filter(|instruction| !instruction.starts_with('('))
+            .map(|instruction| match instruction.strip_prefix('@') {
+                Some(symbol) => Instruction::Address(symbol.parse().unwrap_or_else(|_| {
+                    *symbols.entry(symbol.to_string()).or_insert_with(|| {
+                        next_variable += 1;
+                        next_variable - 1
+                    })
+                })),
+                None => {
+                    let (dest, rest) = instruction.split_once('=').unwrap_or(("", instruction));
+                    let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+                    Instruction::Compute {
+                        dest: dest.to_string(),
+                        comp: comp.to_string(),
+                        jump: jump.to_string(),
+                    }
+                }
+            })
+            .collect();
+
+        Self {
+            ram: vec![0; RAM_SIZE],
+            rom,
+            pc: 0,
+        }
+    }
+
+    //runs until the program falls off its end or spins on a `(END) @END 0;JMP` loop
+    pub fn run(&mut self, max_steps: usize) {
+        let mut a: u16 = 0;
+        let mut d: u16 = 0;
+        for _ in 0..max_steps {
+            let Some(instruction) = self.rom.get(self.pc) else {
+                return;
+            };
+            match instruction {
+                Instruction::Address(value) => {
+                    a = *value;
+                    self.pc += 1;
+                }
+                Instruction::Compute { dest, comp, jump } => {
+                    let m = if comp.contains('M') {
+                        self.ram[a as usize]
+                    } else {
+                        0
+                    };
+                    let value = compute(comp, d, a, m);
+                    if dest.contains('M') {
+                        self.ram[a as usize] = value;
+                    }
+                    let address = a as usize;
+                    if dest.contains('A') {
+                        a = value;
+                    }
+                    if dest.contains('D') {
+                        d = value;
+                    }
+                    if jumps(jump, value) {
+                        if address + 1 == self.pc && jump == "JMP" {
+                            return;
+                        }
+                        self.pc = address;
+                    } else {
+                        self.pc += 1;
+                    }
+                }
+            }
+        }
+        panic!("program did not halt after {} steps", max_steps);
+    }
+}
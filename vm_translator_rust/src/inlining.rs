@@ -0,0 +1,447 @@
+//! Inlining of small leaf functions at their call sites.
+//!
+//! A leaf function calls nothing, so at most one of its invocations is running at any time
+//! and its arguments and locals can live in fixed slots instead of a stack frame. Those
+//! slots are `static` variables past the last one the caller's file uses, shared by every
+//! function inlined in that file: the arguments are popped into them at the call site and
+//! the callee's `argument` and `local` accesses are remapped onto them. A call whose slots
+//! would run past the static variables is left as it is.
+//!
+//! The callee's `return` must leave exactly one value on its working stack, since an inlined
+//! body has no frame to reset the stack pointer with, and the THIS/THAT pointers it changes
+//! are saved and restored around the body like a return would.
+
+use std::collections::HashMap;
+
+use crate::options::Options;
+use crate::parser::{Command, VmFile, STATIC_SIZE};
+
+struct Callee<'a> {
+    filename: &'a str,
+    number_of_local_variables: usize,
+    body: &'a [Command],
+    uses_static: bool,
+    //pointer 0 (THIS) and pointer 1 (THAT)
+    writes_pointer: [bool; 2],
+    //smallest number of arguments the body needs
+    number_of_args: usize,
+}
+
+fn record_label_depth<'a>(
+    label_depths: &mut HashMap<&'a str, usize>,
+    label: &'a str,
+    depth: usize,
+) -> bool {
+    *label_depths.entry(label).or_insert(depth) == depth
+}
+
+//whether every `return` of the body is reached with a single value on the working stack
+fn returns_single_value(body: &[Command]) -> bool {
+    let mut label_depths: HashMap<&str, usize> = HashMap::new();
+    //None while the code is unreachable, after a goto or a return
+    let mut depth = Some(0usize);
+
+    for command in body {
+        if let Command::Label(label) = command {
+            depth = match (depth, label_depths.get(label.as_str())) {
+                (Some(depth), Some(expected)) if depth != *expected => return false,
+                (Some(depth), _) | (None, Some(&depth)) => Some(depth),
+                //only reachable through a backward jump, the depth is unknown
+                (None, None) => return false,
+            };
+            label_depths.insert(label, depth.unwrap());
+            continue;
+        }
+        let Some(current) = depth else {
+            continue;
+        };
+        match command {
+            Command::Return => {
+                if current != 1 {
+                    return false;
+                }
+                depth = None;
+            }
+            Command::Goto(label) => {
+                if !record_label_depth(&mut label_depths, label, current) {
+                    return false;
+                }
+                depth = None;
+            }
+            command => {
+                let Some((pops, pushes)) = command.stack_effect() else {
+                    return false;
+                };
+                let Some(remaining) = current.checked_sub(pops) else {
+                    return false;
+                };
+                if let Command::IfGoto(label) = command {
+                    if !record_label_depth(&mut label_depths, label, remaining) {
+                        return false;
+                    }
+                }
+                depth = Some(remaining + pushes);
+            }
+        }
+    }
+
+    depth.is_none()
+}
+
+fn inlinable_functions<'a>(files: &'a [VmFile], options: &Options) -> HashMap<&'a str, Callee<'a>> {
+    let mut callees = HashMap::new();
+    for file in files {
+        for (name, commands) in file.functions() {
+            let Command::Function {
+                number_of_local_variables,
+                ..
+            } = &commands[0]
+            else {
+                unreachable!()
+            };
+            let body = &commands[1..];
+            let size = body
+                .iter()
+                .filter(|command| !matches!(command, Command::Label(_)))
+                .count();
+            if size > options.inline_threshold
+                || name == options.entry
+                || options.no_inline.iter().any(|function| function == name)
+                || body
+                    .iter()
+                    .any(|command| matches!(command, Command::Call { .. } | Command::Asm { .. }))
+                || !returns_single_value(body)
+            {
+                continue;
+            }
+
+            let mut callee = Callee {
+                filename: &file.filename,
+                number_of_local_variables: *number_of_local_variables,
+                body,
+                uses_static: false,
+                writes_pointer: [false; 2],
+                number_of_args: 0,
+            };
+            for command in body {
+                match command {
+                    Command::Push {
+                        memory_segment,
+                        index,
+                    }
+                    | Command::Pop {
+                        memory_segment,
+                        index,
+                    } => match memory_segment.as_str() {
+                        "static" => callee.uses_static = true,
+                        "argument" => {
+                            callee.number_of_args = callee.number_of_args.max(*index as usize + 1)
+                        }
+                        "pointer" if matches!(command, Command::Pop { .. }) => {
+                            callee.writes_pointer[(*index as usize).min(1)] = true
+                        }
+                        //THIS and THAT written through their addresses
+                        "ram"
+                            if matches!(command, Command::Pop { .. }) && matches!(index, 3 | 4) =>
+                        {
+                            callee.writes_pointer[*index as usize - 3] = true
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+            callees.insert(name, callee);
+        }
+    }
+    callees
+}
+
+//first static index no command of the file uses
+fn static_base(file: &VmFile) -> usize {
+    file.commands
+        .iter()
+        .filter_map(|command| match command {
+            Command::Push {
+                memory_segment,
+                index,
+            }
+            | Command::Pop {
+                memory_segment,
+                index,
+            } if memory_segment == "static" => Some(usize::from(*index) + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn static_slot(index: u16) -> (String, u16) {
+    ("static".to_string(), index)
+}
+
+struct Expansion<'a> {
+    name: &'a str,
+    callee: &'a Callee<'a>,
+    number_of_args: usize,
+    //slots: saved THIS, saved THAT, then the arguments followed by the locals
+    base: u16,
+    id: usize,
+}
+
+impl Expansion<'_> {
+    fn label(&self, label: &str) -> String {
+        format!("{}$inline.{}.{}", self.name, self.id, label)
+    }
+
+    fn remap(&self, memory_segment: &str, index: u16) -> (String, u16) {
+        let arguments = self.base + 2;
+        match memory_segment {
+            "argument" => static_slot(arguments + index),
+            "local" => static_slot(arguments + self.number_of_args as u16 + index),
+            _ => (memory_segment.to_string(), index),
+        }
+    }
+
+    fn write(&self, inlined: &mut Vec<Command>) {
+        for pointer in 0..2 {
+            if self.callee.writes_pointer[pointer] {
+                let (memory_segment, index) = static_slot(self.base + pointer as u16);
+                inlined.push(Command::Push {
+                    memory_segment: "pointer".to_string(),
+                    index: pointer as u16,
+                });
+                inlined.push(Command::Pop {
+                    memory_segment,
+                    index,
+                });
+            }
+        }
+        for argument in (0..self.number_of_args as u16).rev() {
+            let (memory_segment, index) = self.remap("argument", argument);
+            inlined.push(Command::Pop {
+                memory_segment,
+                index,
+            });
+        }
+        for local in 0..self.callee.number_of_local_variables as u16 {
+            let (memory_segment, index) = self.remap("local", local);
+            inlined.push(Command::Push {
+                memory_segment: "constant".to_string(),
+                index: 0,
+            });
+            inlined.push(Command::Pop {
+                memory_segment,
+                index,
+            });
+        }
+
+        let end = self.label("end");
+        let last = self.callee.body.len() - 1;
+        let mut jumps_to_end = false;
+        for (i, command) in self.callee.body.iter().enumerate() {
+            inlined.push(match command {
+                Command::Push {
+                    memory_segment,
+                    index,
+                } => {
+                    let (memory_segment, index) = self.remap(memory_segment, *index);
+                    Command::Push {
+                        memory_segment,
+                        index,
+                    }
+                }
+                Command::Pop {
+                    memory_segment,
+                    index,
+                } => {
+                    let (memory_segment, index) = self.remap(memory_segment, *index);
+                    Command::Pop {
+                        memory_segment,
+                        index,
+                    }
+                }
+                Command::Label(label) => Command::Label(self.label(label)),
+                Command::Goto(label) => Command::Goto(self.label(label)),
+                Command::IfGoto(label) => Command::IfGoto(self.label(label)),
+                Command::Return if i == last => continue,
+                Command::Return => {
+                    jumps_to_end = true;
+                    Command::Goto(end.clone())
+                }
+                command => command.clone(),
+            });
+        }
+        if jumps_to_end {
+            inlined.push(Command::Label(end));
+        }
+
+        for pointer in 0..2 {
+            if self.callee.writes_pointer[pointer] {
+                let (memory_segment, index) = static_slot(self.base + pointer as u16);
+                inlined.push(Command::Push {
+                    memory_segment,
+                    index,
+                });
+                inlined.push(Command::Pop {
+                    memory_segment: "pointer".to_string(),
+                    index: pointer as u16,
+                });
+            }
+        }
+    }
+}
+
+/// Inlines the calls to the small leaf functions and returns how many were inlined.
+pub fn inline(files: &mut [VmFile], options: &Options) -> usize {
+    let originals = files.to_vec();
+    let callees = inlinable_functions(&originals, options);
+    let mut id = 0;
+
+    for file in files.iter_mut() {
+        let base = static_base(file);
+        let mut inlined = Vec::with_capacity(file.commands.len());
+        for command in file.commands.drain(..) {
+            let Command::Call {
+                name,
+                number_of_args,
+            } = &command
+            else {
+                inlined.push(command);
+                continue;
+            };
+            match callees.get(name.as_str()) {
+                //the saved pointers, arguments and locals must fit in the static variables
+                Some(callee)
+                    if (!callee.uses_static || callee.filename == file.filename)
+                        && callee.number_of_args <= *number_of_args
+                        && base + 2 + number_of_args + callee.number_of_local_variables
+                            <= STATIC_SIZE.into() =>
+                {
+                    Expansion {
+                        name,
+                        callee,
+                        number_of_args: *number_of_args,
+                        base: base as u16,
+                        id,
+                    }
+                    .write(&mut inlined);
+                    id += 1;
+                }
+                _ => inlined.push(command),
+            }
+        }
+        file.commands = inlined;
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::vm_file;
+
+    #[test]
+    fn test_getter_is_inlined() {
+        let mut files = [
+            vm_file(
+                "Main",
+                "function Main.main 0\npush static 0\ncall Point.getX 1\nreturn",
+            ),
+            vm_file(
+                "Point",
+                "function Point.getX 0\npush argument 0\npop pointer 0\npush this 0\nreturn",
+            ),
+        ];
+        assert_eq!(inline(&mut files, &Options::default()), 1);
+        assert_eq!(
+            files[0],
+            vm_file(
+                "Main",
+                "function Main.main 0\npush static 0\n\
+                 push pointer 0\npop static 1\npop static 3\n\
+                 push static 3\npop pointer 0\npush this 0\n\
+                 push static 1\npop pointer 0\nreturn"
+            )
+        );
+    }
+
+    #[test]
+    fn test_labels_and_locals_are_remapped() {
+        let mut files = [vm_file(
+            "Math",
+            "function Math.abs 1\npush argument 0\npop local 0\n\
+             push local 0\npush constant 0\nlt\nif-goto NEG\npush local 0\nreturn\n\
+             label NEG\npush local 0\nneg\nreturn\n\
+             function Main.main 0\npush constant 5\ncall Math.abs 1\nreturn",
+        )];
+        let options = Options {
+            inline_threshold: 16,
+            ..Options::default()
+        };
+        assert_eq!(inline(&mut files, &options), 1);
+        let (_, main) = files[0].functions().nth(1).unwrap();
+        assert_eq!(
+            main,
+            vm_file(
+                "Math",
+                "function Main.main 0\npush constant 5\n\
+                 pop static 2\npush constant 0\npop static 3\n\
+                 push static 2\npop static 3\n\
+                 push static 3\npush constant 0\nlt\nif-goto Math.abs$inline.0.NEG\n\
+                 push static 3\ngoto Math.abs$inline.0.end\n\
+                 label Math.abs$inline.0.NEG\npush static 3\nneg\n\
+                 label Math.abs$inline.0.end\nreturn"
+            )
+            .commands
+        );
+    }
+
+    #[test]
+    fn test_stack_manipulation_keeps_a_single_return_value() {
+        let mut files = [vm_file(
+            "Main",
+            "function Main.square 0\npush argument 0\ndup\nmul\nreturn\n\
+             function Main.second 0\npush argument 0\npush argument 1\nswap\ndrop\nreturn\n\
+             function Main.both 0\npush argument 0\npush argument 1\nover\nreturn\n\
+             function Main.main 0\npush constant 3\ncall Main.square 1\n\
+             push constant 1\npush constant 2\ncall Main.second 2\n\
+             push constant 1\npush constant 2\ncall Main.both 2\nreturn",
+        )];
+        //both leaves three values on its stack
+        assert_eq!(inline(&mut files, &Options::default()), 2);
+    }
+
+    #[test]
+    fn test_not_inlined() {
+        let program = "function Main.recursive 0\ncall Main.recursive 0\nreturn\n\
+                       function Main.unbalanced 0\npush constant 1\npush constant 2\nreturn\n\
+                       function Main.opted_out 0\npush constant 1\nreturn\n\
+                       function Main.assembly 0\nasm 0 1\n@SP\nM=M+1\nendasm\nreturn\n\
+                       function Main.main 0\ncall Main.recursive 0\ncall Main.unbalanced 0\n\
+                       call Main.opted_out 0\ncall Main.assembly 0\nreturn";
+        let mut files = [vm_file("Main", program)];
+        let options = Options {
+            no_inline: vec!["Main.opted_out".to_string()],
+            ..Options::default()
+        };
+        assert_eq!(inline(&mut files, &options), 0);
+        assert_eq!(files[0], vm_file("Main", program));
+    }
+
+    #[test]
+    fn test_slots_past_the_static_variables_are_not_inlined() {
+        //the saved pointers and the argument take three slots past the last static variable
+        for (last, inlined) in [(236, 1), (237, 0)] {
+            let mut files = [vm_file(
+                "Main",
+                &format!(
+                    "function Main.id 0\npush argument 0\nreturn\n\
+                     function Main.main 0\npush static {}\ncall Main.id 1\nreturn",
+                    last
+                ),
+            )];
+            assert_eq!(inline(&mut files, &Options::default()), inlined);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Jump threading and constant branch elimination over the vm commands.
+//!
+//! A jump to a label that is only followed by another `goto` is retargeted at the final
+//! destination, a conditional branch on a constant either disappears or becomes a `goto`,
+//! a `goto` to the label right after it is dropped, and so is the unreachable code between a
+//! `goto` and the next label or asm block. Labels are local to their function, so every
+//! function is rewritten on its own.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::Command;
+
+//value left on the stack by `push constant c` optionally followed by `neg` or `not`, with
+//the number of commands computing it
+fn constant_value(commands: &[Command]) -> Option<(u16, usize)> {
+    let Some(Command::Push {
+        memory_segment,
+        index,
+    }) = commands.first()
+    else {
+        return None;
+    };
+    if memory_segment != "constant" {
+        return None;
+    }
+    match commands.get(1) {
+        Some(Command::Arithmetic(instruction)) if instruction == "neg" => {
+            Some((index.wrapping_neg(), 2))
+        }
+        Some(Command::Arithmetic(instruction)) if instruction == "not" => Some((!index, 2)),
+        _ => Some((*index, 1)),
+    }
+}
+
+//final destination of a jump to the label, following the labels directly followed by a goto
+fn resolve<'a>(commands: &'a [Command], labels: &HashMap<&str, usize>, label: &'a str) -> &'a str {
+    let mut label = label;
+    let mut visited = HashSet::new();
+    while visited.insert(label) {
+        let Some(&position) = labels.get(label) else {
+            break;
+        };
+        match commands[position..]
+            .iter()
+            .find(|command| !matches!(command, Command::Label(_)))
+        {
+            Some(Command::Goto(target)) => label = target,
+            _ => break,
+        }
+    }
+    label
+}
+
+fn is_followed_by_label(commands: &[Command], label: &str) -> bool {
+    commands
+        .iter()
+        .map_while(|command| match command {
+            Command::Label(name) => Some(name),
+            _ => None,
+        })
+        .any(|name| name == label)
+}
+
+//one rewrite of the function, returns the number of jumps changed or removed
+fn thread_function(commands: &mut Vec<Command>) -> usize {
+    let labels: HashMap<&str, usize> = commands
+        .iter()
+        .enumerate()
+        .filter_map(|(i, command)| match command {
+            Command::Label(label) => Some((label.as_str(), i)),
+            _ => None,
+        })
+        .collect();
+
+    let mut threaded = Vec::with_capacity(commands.len());
+    let mut changes = 0;
+    let mut i = 0;
+    while i < commands.len() {
+        if let Some((value, length)) = constant_value(&commands[i..]) {
+            if let Some(Command::IfGoto(label)) = commands.get(i + length) {
+                if value != 0 {
+                    threaded.push(Command::Goto(label.clone()));
+                }
+                changes += 1;
+                i += length + 1;
+                continue;
+            }
+        }
+
+        match &commands[i] {
+            Command::Goto(label) => {
+                let target = resolve(commands, &labels, label);
+                if target != label {
+                    changes += 1;
+                }
+                if is_followed_by_label(&commands[i + 1..], target) {
+                    changes += 1;
+                } else {
+                    threaded.push(Command::Goto(target.to_string()));
+                }
+                //nothing falls through to the commands before the next label, an asm block
+                //may hold labels of its own
+                let unreachable = commands[i + 1..]
+                    .iter()
+                    .take_while(|command| {
+                        !matches!(command, Command::Label(_) | Command::Asm { .. })
+                    })
+                    .count();
+                changes += unreachable;
+                i += unreachable + 1;
+                continue;
+            }
+            Command::IfGoto(label) => {
+                let target = resolve(commands, &labels, label);
+                if target != label {
+                    changes += 1;
+                }
+                threaded.push(Command::IfGoto(target.to_string()));
+            }
+            command => threaded.push(command.clone()),
+        }
+        i += 1;
+    }
+
+    *commands = threaded;
+    changes
+}
+
+/// Threads the jumps of every function until nothing changes, returns the number of jumps
+/// changed or removed.
+pub fn thread_jumps(commands: &mut Vec<Command>) -> usize {
+    let mut functions: Vec<Vec<Command>> = Vec::new();
+    for command in commands.drain(..) {
+        match functions.last_mut() {
+            Some(function) if !matches!(command, Command::Function { .. }) => {
+                function.push(command)
+            }
+            _ => functions.push(vec![command]),
+        }
+    }
+
+    let mut changes = 0;
+    for mut function in functions {
+        loop {
+            let changed = thread_function(&mut function);
+            if changed == 0 {
+                break;
+            }
+            changes += changed;
+        }
+        commands.append(&mut function);
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn thread(program: &str) -> (Vec<Command>, usize) {
+        let mut commands = parser::parse_file(&mut program.as_bytes()).unwrap();
+        let changes = thread_jumps(&mut commands);
+        (commands, changes)
+    }
+
+    fn parse(program: &str) -> Vec<Command> {
+        parser::parse_file(&mut program.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_jump_chains_are_threaded() {
+        let (commands, changes) = thread(
+            "function Main.main 0\nlabel A\npush local 0\nif-goto B\ngoto C\n\
+             label B\nlabel B2\ngoto C\nlabel C\ngoto A",
+        );
+        assert_eq!(
+            commands,
+            parse(
+                "function Main.main 0\nlabel A\npush local 0\nif-goto A\ngoto A\n\
+                 label B\nlabel B2\ngoto A\nlabel C\ngoto A"
+            )
+        );
+        assert_eq!(changes, 3);
+    }
+
+    #[test]
+    fn test_constant_branches_are_removed() {
+        let (commands, _) = thread(
+            "function Main.main 0\npush constant 0\nif-goto A\n\
+             push constant 0\nnot\nif-goto B\npush constant 7\nreturn\n\
+             label A\npush constant 1\nif-goto A\nlabel B\npush constant 0\nreturn",
+        );
+        assert_eq!(
+            commands,
+            parse(
+                "function Main.main 0\ngoto B\n\
+                 label A\ngoto A\nlabel B\npush constant 0\nreturn"
+            )
+        );
+    }
+
+    #[test]
+    fn test_labels_are_local_to_functions() {
+        let program = "function Main.a 0\nlabel LOOP\ngoto END\nlabel END\ngoto LOOP\n\
+                       function Main.b 0\ngoto END\npush constant 1\nlabel END\nreturn";
+        let (commands, changes) = thread(program);
+        assert_eq!(
+            commands,
+            parse(
+                "function Main.a 0\nlabel LOOP\nlabel END\ngoto LOOP\n\
+                 function Main.b 0\nlabel END\nreturn"
+            )
+        );
+        assert_eq!(changes, 3);
+    }
+}
@@ -1,22 +1,67 @@
-use std::io::{BufReader, Seek};
-
+mod constant_folding;
+mod dead_function_elimination;
+#[cfg(test)]
+mod emulator;
+mod inlining;
+mod jump_threading;
 mod memory_segments;
+mod options;
 mod parser;
+mod passes;
+mod peephole;
+mod size_report;
 mod writer;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().collect();
-
-    if args.len() != 2 {
-        return Err("cargo run <filename>".into());
+fn main() -> std::process::ExitCode {
+    match translate() {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::ExitCode::FAILURE
+        }
     }
+}
 
-    let filename = &args[1];
+fn translate() -> Result<(), Box<dyn std::error::Error>> {
+    let options = options::Options::parse(std::env::args().skip(1))?;
 
-    let file = std::fs::File::open(filename)?;
-    let mut reader = BufReader::new(file);
-    parser::parse_file(&mut reader)?;
-    reader.rewind()?;
-    writer::write_hack_instruction_from_jvm_instruction_into_file(reader, filename)?;
+    let constants = match &options.constants {
+        Some(path) => parser::parse_constants_file(path)?,
+        None => parser::Constants::new(),
+    };
+    let mut files = parser::parse_program(&options.filename, &constants, &options.include_paths)?;
+    let mut statistics = passes::run_vm_passes(&mut files, &options);
+    let mut instructions = writer::translate(&files, &options);
+    statistics.extend(passes::run_hack_passes(&mut instructions, &options));
+    let size_report = size_report::SizeReport::new(&files, &instructions);
+    if options.size_report {
+        print!("{}", size_report);
+    }
+    size_report.check_budget(options.rom_budget)?;
+    writer::write_hack_instructions_into_file(&instructions, &options)?;
+
+    for statistics in &statistics {
+        println!("{}", statistics);
+        if options.verbose {
+            for detail in &statistics.details {
+                println!("  {}", detail);
+            }
+        }
+    }
+    if options.optimization == options::Optimization::Size {
+        let inlined = passes::count_hack_instructions(
+            &files,
+            &options::Options {
+                optimization: options::Optimization::Speed,
+                ..options.clone()
+            },
+        );
+        println!(
+            "opt-size: {} -> {} instructions ({} saved)",
+            inlined,
+            size_report.total,
+            inlined as isize - size_report.total as isize
+        );
+    }
     Ok(())
 }
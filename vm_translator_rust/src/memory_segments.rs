@@ -11,6 +11,7 @@ lazy_static! {
         ("temp", "5"),
         ("static", "STATIC"),
         ("pointer", "POINTER"),
-        ("constant", "CONSTANT")
+        ("constant", "CONSTANT"),
+        ("ram", "0")
     ]);
 }
@@ -0,0 +1,194 @@
+//! Command line options of the translator.
+
+use std::collections::BTreeSet;
+
+use crate::passes::{self, Pass};
+use crate::size_report::ROM_SIZE;
+
+pub const USAGE: &str = "cargo run <filename | directory> [--opt-size | --opt-speed] \
+                         [--entry <function>] [--inline-threshold <n>] \
+                         [--no-inline <function>]... [--unsafe-compare] \
+                         [--cache-top-of-stack] [-O0 | -O1 | -O2 | -O3] \
+                         [--pass <pass>]... [--no-pass <pass>]... \
+                         [--rom-budget <n>] [--size-report] [--no-tail-calls] \
+                         [--constants <file>] [--include-path <directory>]... [-v]";
+
+pub const DEFAULT_ENTRY: &str = "Sys.init";
+pub const DEFAULT_INLINE_THRESHOLD: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Optimization {
+    /// Inline every template, the default.
+    #[default]
+    Speed,
+    /// Share the long templates between their uses through runtime routines.
+    Size,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// A single .vm file, or a directory whose .vm files are linked together.
+    pub filename: String,
+    pub optimization: Optimization,
+    /// File of `const` directives every .vm file of the program can use.
+    pub constants: Option<String>,
+    /// Directories searched, in order, for the files of `include` directives not found next to
+    /// the file including them.
+    pub include_paths: Vec<String>,
+    /// Function the bootstrap code calls, and root of the dead function elimination.
+    pub entry: String,
+    /// Biggest leaf function, in commands, inlined at its call sites, 0 disables inlining.
+    pub inline_threshold: usize,
+    /// Functions never inlined.
+    pub no_inline: Vec<String>,
+    /// Whether lt/gt check the operand signs to give the right answer when the subtraction
+    /// overflows, which costs about twenty instructions per comparison.
+    pub safe_compare: bool,
+    /// Whether a `call` right before a `return` reuses the frame of the current function,
+    /// off at -O0 to keep every frame around when debugging.
+    pub tail_calls: bool,
+    /// Keep the top of the stack in D between consecutive commands instead of RAM, on by
+    /// default at -O3.
+    pub cache_top_of_stack: bool,
+    /// Optimization passes to run, chosen by the -O level then the --pass/--no-pass flags.
+    pub passes: BTreeSet<Pass>,
+    /// Most instructions the translated program may have, the whole ROM by default.
+    pub rom_budget: usize,
+    /// Print the size of every function and file.
+    pub size_report: bool,
+    pub verbose: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            filename: String::new(),
+            optimization: Optimization::default(),
+            constants: None,
+            include_paths: Vec::new(),
+            entry: DEFAULT_ENTRY.to_string(),
+            inline_threshold: DEFAULT_INLINE_THRESHOLD,
+            no_inline: Vec::new(),
+            safe_compare: true,
+            tail_calls: true,
+            cache_top_of_stack: false,
+            passes: passes::passes_of_level(passes::DEFAULT_OPT_LEVEL),
+            rom_budget: ROM_SIZE,
+            size_report: false,
+            verbose: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    MissingFilename,
+    MissingValue { flag: String },
+    InvalidValue { flag: String, value: String },
+    UnknownFlag { flag: String },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::MissingFilename => write!(f, "missing filename, usage: {USAGE}"),
+            Error::MissingValue { flag } => write!(f, "missing value for {flag}, usage: {USAGE}"),
+            Error::InvalidValue { flag, value } => {
+                write!(f, "invalid value {value} for {flag}, usage: {USAGE}")
+            }
+            Error::UnknownFlag { flag } => write!(f, "unknown flag {flag}, usage: {USAGE}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Options {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut options = Options::default();
+        let mut filename = None;
+        let mut level = passes::DEFAULT_OPT_LEVEL;
+        //applied over the passes of the level, in command line order
+        let mut pass_flags = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--opt-size" => options.optimization = Optimization::Size,
+                "--opt-speed" => options.optimization = Optimization::Speed,
+                "--entry" => {
+                    options.entry = args.next().ok_or(Error::MissingValue { flag: arg })?;
+                }
+                "--constants" => {
+                    options.constants = Some(args.next().ok_or(Error::MissingValue { flag: arg })?);
+                }
+                "-I" | "--include-path" => {
+                    let directory = args.next().ok_or(Error::MissingValue { flag: arg })?;
+                    options.include_paths.push(directory);
+                }
+                "--inline-threshold" => {
+                    let value = args
+                        .next()
+                        .ok_or(Error::MissingValue { flag: arg.clone() })?;
+                    options.inline_threshold = value
+                        .parse()
+                        .map_err(|_| Error::InvalidValue { flag: arg, value })?;
+                }
+                "--no-inline" => {
+                    let function = args.next().ok_or(Error::MissingValue { flag: arg })?;
+                    options.no_inline.push(function);
+                }
+                "--unsafe-compare" => options.safe_compare = false,
+                "--cache-top-of-stack" => options.cache_top_of_stack = true,
+                "--no-tail-calls" => options.tail_calls = false,
+                "--pass" | "--no-pass" => {
+                    let name = args
+                        .next()
+                        .ok_or(Error::MissingValue { flag: arg.clone() })?;
+                    let pass = Pass::from_name(&name).ok_or(Error::InvalidValue {
+                        flag: arg.clone(),
+                        value: name,
+                    })?;
+                    pass_flags.push((pass, arg == "--pass"));
+                }
+                "--rom-budget" => {
+                    let value = args
+                        .next()
+                        .ok_or(Error::MissingValue { flag: arg.clone() })?;
+                    options.rom_budget = value
+                        .parse()
+                        .map_err(|_| Error::InvalidValue { flag: arg, value })?;
+                }
+                "--size-report" => options.size_report = true,
+                "-v" | "--verbose" => options.verbose = true,
+                flag if flag.starts_with("-O") => {
+                    let value = flag["-O".len()..].to_string();
+                    level = value
+                        .parse()
+                        .ok()
+                        .filter(|level| *level <= passes::MAX_OPT_LEVEL)
+                        .ok_or(Error::InvalidValue {
+                            flag: "-O".to_string(),
+                            value,
+                        })?;
+                }
+                flag if flag.starts_with("--") || filename.is_some() => {
+                    return Err(Error::UnknownFlag { flag: arg });
+                }
+                _ => filename = Some(arg),
+            }
+        }
+
+        options.filename = filename.ok_or(Error::MissingFilename)?;
+        options.passes = passes::passes_of_level(level);
+        for (pass, enabled) in pass_flags {
+            if enabled {
+                options.passes.insert(pass);
+            } else {
+                options.passes.remove(&pass);
+            }
+        }
+        options.tail_calls &= level > 0;
+        options.cache_top_of_stack |= level >= passes::MAX_OPT_LEVEL;
+        Ok(options)
+    }
+}
@@ -1,123 +1,1710 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 
 use crate::memory_segments::MEMORY_SEGMENTS;
 
-const INSTRUCTIONS: [&str; 17] = [
+const INSTRUCTIONS: [&str; 30] = [
     "push", "pop", "add", "sub", "eq", "lt", "gt", "and", "or", "not", "neg", "if-goto", "goto",
-    "label", "call", "function", "return",
+    "label", "call", "function", "return", "mul", "div", "mod", "shl", "shr", "ushr", "ne", "lte",
+    "gte", "dup", "swap", "drop", "over",
 ];
+//directives of the parser, which can't name a macro either
+const KEYWORDS: [&str; 6] = ["const", "include", "macro", "endmacro", "asm", "endasm"];
+
+//mnemonics of the Hack C-instruction fields
+const COMPUTATIONS: [&str; 28] = [
+    "0", "1", "-1", "D", "A", "!D", "!A", "-D", "-A", "D+1", "A+1", "D-1", "A-1", "D+A", "D-A",
+    "A-D", "D&A", "D|A", "M", "!M", "-M", "M+1", "M-1", "D+M", "D-M", "M-D", "D&M", "D|M",
+];
+const DESTINATIONS: [&str; 8] = ["M", "D", "MD", "DM", "A", "AM", "AD", "AMD"];
+const JUMPS: [&str; 7] = ["JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
+//symbols the Hack assembler predefines, besides R0 to R15
+const HACK_SYMBOLS: [(&str, u16); 7] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+//the keyboard is the last word of the RAM
+const MAX_RAM_ADDRESS: u16 = 24576;
+
+//Hack character codes of the keys outside of printable ASCII
+const NEW_LINE: u16 = 128;
+const BACKSPACE: u16 = 129;
+//F12, the last key of the Hack character set
+const LAST_KEY: u16 = 152;
 
 #[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
-    UnknownInstruction { instruction: String },
-    UnknownMemorySegement { memory_segment: String },
-    WrongSyntax { expected: String },
+    UnknownInstruction {
+        instruction: String,
+    },
+    UnknownMemorySegement {
+        memory_segment: String,
+    },
+    WrongSyntax {
+        expected: String,
+    },
+    UndefinedConstant {
+        name: String,
+    },
+    RedefinedConstant {
+        name: String,
+    },
+    RedefinedMacro {
+        name: String,
+    },
+    UnterminatedMacro {
+        name: String,
+    },
+    UnterminatedAsm,
+    InvalidCharacter {
+        character: char,
+    },
+    InvalidAssembly {
+        instruction: String,
+    },
+    RecursiveMacro {
+        name: String,
+    },
+    MacroArguments {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// An error in the expansion of a macro, at the line of its use.
+    InMacro {
+        name: String,
+        line: usize,
+        error: Box<Error>,
+    },
+    IncludeNotFound {
+        path: String,
+    },
+    IncludeCycle {
+        path: String,
+    },
+    /// An error inside an included file, with the include directives that led to it from the
+    /// innermost one.
+    Included {
+        file: String,
+        error: Box<Error>,
+        chain: Vec<Inclusion>,
+    },
+}
+
+/// Where an `include` directive is, lines counted from 1.
+#[derive(Debug)]
+pub struct Inclusion {
+    pub file: String,
+    pub line: usize,
 }
 
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::UnknownInstruction { instruction } => {
+                write!(f, "unknown instruction: {instruction}")
+            }
+            Error::UnknownMemorySegement { memory_segment } => {
+                write!(f, "unknown memory segment: {memory_segment}")
+            }
+            Error::WrongSyntax { expected } => write!(f, "wrong syntax, expected: {expected}"),
+            Error::UndefinedConstant { name } => write!(f, "undefined constant: {name}"),
+            Error::RedefinedConstant { name } => write!(f, "constant defined twice: {name}"),
+            Error::RedefinedMacro { name } => write!(f, "macro defined twice: {name}"),
+            Error::UnterminatedMacro { name } => write!(f, "macro {name} misses its endmacro"),
+            Error::InvalidCharacter { character } => {
+                write!(f, "{character:?} is not in the Hack character set")
+            }
+            Error::UnterminatedAsm => write!(f, "asm block misses its endasm"),
+            Error::InvalidAssembly { instruction } => {
+                write!(f, "invalid Hack instruction: {instruction}")
+            }
+            Error::RecursiveMacro { name } => write!(f, "macro {name} expands into itself"),
+            Error::MacroArguments {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "macro {name} takes {expected} arguments but {found} were given"
+            ),
+            Error::InMacro { name, line, error } => {
+                write!(f, "{error}\n  in macro {name} used at line {line}")
+            }
+            Error::IncludeNotFound { path } => write!(f, "included file not found: {path}"),
+            Error::IncludeCycle { path } => write!(f, "{path} ends up including itself"),
+            Error::Included { file, error, chain } => {
+                write!(f, "{file}: {error}")?;
+                for inclusion in chain {
+                    write!(f, "\n  included from {}:{}", inclusion.file, inclusion.line)?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
 impl std::error::Error for Error {}
 
-pub fn parse_file(reader: &mut BufReader<File>) -> Result<(), Error> {
-    let mut line = String::new();
+/// Biggest value an A-instruction can load, `push constant` goes up to `u16::MAX`.
+pub const MAX_CONSTANT: u16 = 32767;
+
+/// Words of the static variables, from RAM[16] up to the stack.
+pub const STATIC_SIZE: u16 = 240;
+
+/// Values of the `const NAME value` directives, by name.
+pub type Constants = HashMap<String, u16>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Push {
+        memory_segment: String,
+        index: u16,
+    },
+    Pop {
+        memory_segment: String,
+        index: u16,
+    },
+    Arithmetic(String),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function {
+        name: String,
+        number_of_local_variables: usize,
+    },
+    Call {
+        name: String,
+        number_of_args: usize,
+    },
+    Return,
+    /// Hack instructions of an `asm ... endasm` block, written as they are, with the numbers
+    /// of values they pop then push when declared by `asm <pops> <pushes>`.
+    Asm {
+        instructions: Vec<String>,
+        stack_effect: Option<(usize, usize)>,
+    },
+}
+
+impl Command {
+    /// Number of values popped off then pushed onto the stack, `None` for `function` and
+    /// `return` whose effect isn't local to the function and for asm blocks not declaring it.
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        match self {
+            Command::Push { .. } => Some((0, 1)),
+            Command::Pop { .. } | Command::IfGoto(_) => Some((1, 0)),
+            Command::Arithmetic(instruction) => Some(match instruction.as_str() {
+                "neg" | "not" => (1, 1),
+                "dup" => (1, 2),
+                "swap" => (2, 2),
+                "drop" => (1, 0),
+                "over" => (2, 3),
+                _ => (2, 1),
+            }),
+            Command::Label(_) | Command::Goto(_) => Some((0, 0)),
+            Command::Call { number_of_args, .. } => Some((*number_of_args, 1)),
+            Command::Function { .. } | Command::Return => None,
+            Command::Asm { stack_effect, .. } => *stack_effect,
+        }
+    }
+}
+
+/// The commands of one .vm file, its name scopes the static segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmFile {
+    pub filename: String,
+    pub commands: Vec<Command>,
+}
+
+impl VmFile {
+    /// Every function of the file with its commands, from its `function` command up to the
+    /// next one or the end of the file.
+    pub fn functions(&self) -> impl Iterator<Item = (&str, &[Command])> {
+        let starts: Vec<usize> = self
+            .commands
+            .iter()
+            .enumerate()
+            .filter(|(_, command)| matches!(command, Command::Function { .. }))
+            .map(|(i, _)| i)
+            .collect();
+        (0..starts.len()).map(move |n| {
+            let end = starts.get(n + 1).copied().unwrap_or(self.commands.len());
+            let body = &self.commands[starts[n]..end];
+            match &body[0] {
+                Command::Function { name, .. } => (name.as_str(), body),
+                _ => unreachable!(),
+            }
+        })
+    }
+}
+
+fn is_constant_name(token: &str) -> bool {
+    token
+        .chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+}
+
+//a decimal, `0x` hexadecimal or `0b` binary literal of 16 bits, the signed ones being
+//stored in two's complement
+fn parse_literal(token: &str, signed: bool) -> Option<u16> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) if signed => (true, digits),
+        _ => (false, token),
+    };
+    let value = if let Some(digits) = digits.strip_prefix("0x") {
+        u16::from_str_radix(digits, 16).ok()?
+    } else if let Some(digits) = digits.strip_prefix("0b") {
+        u16::from_str_radix(digits, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    match negative {
+        true if value > 1 << 15 => None,
+        true => Some(value.wrapping_neg()),
+        false => Some(value),
+    }
+}
+
+//a literal or the name of a constant of the file or of the project, None when it is neither
+fn resolve_number(
+    token: &str,
+    signed: bool,
+    constants: &Constants,
+    project_constants: &Constants,
+) -> Result<Option<u16>, Error> {
+    if !is_constant_name(token) {
+        return Ok(parse_literal(token, signed));
+    }
+    constants
+        .get(token)
+        .or_else(|| project_constants.get(token))
+        .map(|value| Some(*value))
+        .ok_or_else(|| Error::UndefinedConstant {
+            name: token.to_string(),
+        })
+}
+
+fn hack_symbol(name: &str) -> Option<u16> {
+    HACK_SYMBOLS
+        .iter()
+        .find(|(symbol, _)| *symbol == name)
+        .map(|(_, address)| *address)
+        .or_else(|| (0..16).find(|register| name == format!("R{}", register)))
+}
+
+//`ram` addresses are sums and differences of literals, constants and Hack symbols such as
+//SCREEN+32, None when they aren't or fall outside of the RAM
+fn resolve_address(
+    token: &str,
+    constants: &Constants,
+    project_constants: &Constants,
+) -> Result<Option<u16>, Error> {
+    let mut address: i32 = 0;
+    let mut sign = 1;
+    let mut rest = token;
     loop {
-        match reader.read_line(&mut line) {
-            Ok(0) => break,
-            Ok(_) if line.starts_with('/') => {}
-            Ok(_) => {
-                let mut is_comment = false;
-                let mut splitted_instruction = line.split_whitespace().filter(|str| {
-                    if is_comment.eq(&true) {
-                        false
-                    } else if str.starts_with('/') {
-                        is_comment = true;
-                        false
-                    } else {
-                        true
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = &rest[..end];
+        let value = match hack_symbol(term) {
+            Some(address)
+                if !constants.contains_key(term) && !project_constants.contains_key(term) =>
+            {
+                Some(address)
+            }
+            _ => resolve_number(term, false, constants, project_constants)?,
+        };
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        address += sign * i32::from(value);
+        match rest[end..].chars().next() {
+            Some(operator) => {
+                sign = if operator == '+' { 1 } else { -1 };
+                rest = &rest[end + 1..];
+            }
+            None => break,
+        }
+    }
+    Ok(u16::try_from(address)
+        .ok()
+        .filter(|address| *address <= MAX_RAM_ADDRESS))
+}
+
+//`const NAME value`, where value may itself be a constant defined above
+fn define_constant<'a>(
+    mut tokens: impl Iterator<Item = &'a str>,
+    constants: &mut Constants,
+    project_constants: &Constants,
+) -> Result<(), Error> {
+    let wrong_syntax = || Error::WrongSyntax {
+        expected: format!(
+            "const <name> <value> where name starts with a letter and value is a number \
+             between {} and {}",
+            i16::MIN,
+            u16::MAX
+        ),
+    };
+    let (Some(name), Some(value)) = (tokens.next(), tokens.next()) else {
+        return Err(wrong_syntax());
+    };
+    if !is_constant_name(name) {
+        return Err(wrong_syntax());
+    }
+    let value =
+        resolve_number(value, true, constants, project_constants)?.ok_or_else(wrong_syntax)?;
+    if constants.contains_key(name) || project_constants.contains_key(name) {
+        return Err(Error::RedefinedConstant {
+            name: name.to_string(),
+        });
+    }
+    constants.insert(name.to_string(), value);
+    Ok(())
+}
+
+fn parse_command<'a>(
+    instruction: &str,
+    mut splitted_instruction: impl Iterator<Item = &'a str>,
+    constants: &Constants,
+    project_constants: &Constants,
+) -> Result<Command, Error> {
+    let command = match instruction {
+        "label" | "if-goto" | "goto" => {
+            let Some(destination) = splitted_instruction.next() else {
+                return Err(Error::WrongSyntax {
+                    expected: { format!("{} <destination>", instruction) },
+                });
+            };
+            let destination = destination.to_string();
+            match instruction {
+                "label" => Command::Label(destination),
+                "goto" => Command::Goto(destination),
+                _ => Command::IfGoto(destination),
+            }
+        }
+        "push" | "pop" => {
+            let Some(memory_segment) = splitted_instruction.next() else {
+                return Err(Error::WrongSyntax {
+                    expected: format!("{} <segments> <i>", instruction),
+                });
+            };
+            if memory_segment == "constant" && instruction == "pop" {
+                return Err(Error::WrongSyntax {
+                    expected: "push constant <i> instead of pop constant <i>".to_string(),
+                });
+            } else if !MEMORY_SEGMENTS.contains_key(memory_segment) {
+                return Err(Error::UnknownMemorySegement {
+                    memory_segment: memory_segment.to_string(),
+                });
+            }
+            let index = splitted_instruction
+                .next()
+                .map(|index| match memory_segment {
+                    "ram" => resolve_address(index, constants, project_constants),
+                    _ => {
+                        let signed = memory_segment == "constant";
+                        resolve_number(index, signed, constants, project_constants)
                     }
+                })
+                .transpose()?
+                .flatten()
+                .ok_or_else(|| Error::WrongSyntax {
+                    expected: format!(
+                        "{} <segments> <i> where i must be a number between 0 and {}, or \
+                         between {} and {} for a constant, or an address of at most {} like \
+                         SCREEN+32 for ram",
+                        instruction,
+                        MAX_CONSTANT,
+                        i16::MIN,
+                        u16::MAX,
+                        MAX_RAM_ADDRESS
+                    ),
+                })?;
+            //the index of a segment goes through an A-instruction, temp, pointer and static being
+            //fixed windows of the ram, R5-R12, R3-R4 and RAM[16..256]
+            let size = match memory_segment {
+                "temp" => Some(8),
+                "pointer" => Some(2),
+                "static" => Some(STATIC_SIZE),
+                "constant" | "ram" => None,
+                _ => Some(MAX_CONSTANT + 1),
+            };
+            if let Some(size) = size.filter(|&size| index >= size) {
+                return Err(Error::WrongSyntax {
+                    expected: format!(
+                        "{} {} <i> where i must be a number between 0 and {}",
+                        instruction,
+                        memory_segment,
+                        size - 1
+                    ),
                 });
-                if let Some(instruction) = splitted_instruction.next() {
-                    match instruction {
-                        "label" | "if-goto" | "goto" => {
-                            if splitted_instruction.next().is_none() {
-                                return Err(Error::WrongSyntax {
-                                    expected: { format!("{} <destination>", instruction) },
-                                });
-                            }
-                        }
-                        "push" | "pop" => {
-                            if let Some(memory_segment) = splitted_instruction.next() {
-                                if memory_segment == "constant" && instruction == "pop" {
-                                    return Err(Error::WrongSyntax {
-                                        expected: "push constant <i> instead of pop constant <i>"
-                                            .to_string(),
-                                    });
-                                } else if !MEMORY_SEGMENTS.contains_key(memory_segment) {
-                                    return Err(Error::UnknownMemorySegement {
-                                        memory_segment: memory_segment.to_string(),
-                                    });
-                                }
-                                if splitted_instruction.next().is_none() {
-                                    return Err(Error::WrongSyntax {
-                                        expected: format!("{} <segments> <i>", instruction),
-                                    });
-                                }
-                            } else {
-                                return Err(Error::WrongSyntax {
-                                    expected: format!("{} <segments> <i>", instruction),
-                                });
-                            }
-                        }
-                        "call" | "function" => {
-                            let function_name = splitted_instruction.next();
-                            let args = splitted_instruction.next();
-                            if function_name.is_none()
-                                || args.is_none()
-                                || args.unwrap().parse::<usize>().is_err()
-                            {
-                                return Err(Error::WrongSyntax {
-                                    expected: format!(
-                                        "{} <functionName> <i> where is must be a positive number",
-                                        instruction
-                                    ),
-                                });
-                            }
+            }
+            let memory_segment = memory_segment.to_string();
+            if instruction == "push" {
+                Command::Push {
+                    memory_segment,
+                    index,
+                }
+            } else {
+                Command::Pop {
+                    memory_segment,
+                    index,
+                }
+            }
+        }
+        "call" | "function" => {
+            let function_name = splitted_instruction.next();
+            let args = splitted_instruction
+                .next()
+                .map(|args| resolve_number(args, false, constants, project_constants))
+                .transpose()?
+                .flatten()
+                .map(usize::from);
+            let (Some(function_name), Some(args)) = (function_name, args) else {
+                return Err(Error::WrongSyntax {
+                    expected: format!(
+                        "{} <functionName> <i> where is must be a positive number",
+                        instruction
+                    ),
+                });
+            };
+            let name = function_name.to_string();
+            if instruction == "call" {
+                Command::Call {
+                    name,
+                    number_of_args: args,
+                }
+            } else {
+                Command::Function {
+                    name,
+                    number_of_local_variables: args,
+                }
+            }
+        }
+        "return" => Command::Return,
+        instruction if !INSTRUCTIONS.contains(&instruction) => {
+            return Err(Error::UnknownInstruction {
+                instruction: instruction.to_string(),
+            });
+        }
+        _ => Command::Arithmetic(instruction.to_string()),
+    };
+    Ok(command)
+}
+
+fn is_symbol(symbol: &str) -> bool {
+    symbol
+        .chars()
+        .next()
+        .is_some_and(|first| !first.is_ascii_digit())
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+}
+
+//a label, an A-instruction or a C-instruction as the Hack assembler accepts them
+fn is_hack_instruction(instruction: &str) -> bool {
+    if let Some(label) = instruction.strip_prefix('(') {
+        return label.strip_suffix(')').is_some_and(is_symbol);
+    }
+    if let Some(value) = instruction.strip_prefix('@') {
+        return value
+            .parse::<u16>()
+            .is_ok_and(|value| value <= MAX_CONSTANT)
+            || is_symbol(value);
+    }
+    let (dest, rest) = match instruction.split_once('=') {
+        Some((dest, rest)) => (Some(dest), rest),
+        None => (None, instruction),
+    };
+    let (comp, jump) = match rest.split_once(';') {
+        Some((comp, jump)) => (comp, Some(jump)),
+        None => (rest, None),
+    };
+    dest.is_none_or(|dest| DESTINATIONS.contains(&dest))
+        && COMPUTATIONS.contains(&comp)
+        && jump.is_none_or(|jump| JUMPS.contains(&jump))
+}
+
+//`asm` or `asm <pops> <pushes>`
+fn begin_asm<'a>(
+    mut tokens: impl Iterator<Item = &'a str>,
+    scope: &mut FileScope,
+    project_constants: &Constants,
+) -> Result<(), Error> {
+    let mut count = || {
+        tokens
+            .next()
+            .map(|token| resolve_number(token, false, &scope.constants, project_constants))
+            .transpose()
+            .map(|count| count.map(|count| count.map(usize::from)))
+    };
+    let stack_effect = match (count()?, count()?, count()?) {
+        (None, None, None) => None,
+        (Some(Some(pops)), Some(Some(pushes)), None) => Some((pops, pushes)),
+        _ => {
+            return Err(Error::WrongSyntax {
+                expected: "asm [<pops> <pushes>]".to_string(),
+            })
+        }
+    };
+    scope.assembling = Some(Command::Asm {
+        instructions: Vec::new(),
+        stack_effect,
+    });
+    Ok(())
+}
+
+/// A `macro` definition, its body holding one command per line.
+#[derive(Debug, Clone)]
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<String>,
+}
+
+//what a file defines for itself, the files it includes having their own
+#[derive(Default)]
+struct FileScope {
+    constants: Constants,
+    macros: HashMap<String, Macro>,
+    //the macro whose body is being read
+    defining: Option<(String, Macro)>,
+    //the macros being expanded, innermost last
+    expanding: Vec<String>,
+    //the asm block being read
+    assembling: Option<Command>,
+}
+
+//adds the commands to the body of the macro being defined, which ends at endmacro
+fn read_macro_body(code: &str, scope: &mut FileScope) -> Result<(), Error> {
+    let Some((_, definition)) = scope.defining.as_mut() else {
+        unreachable!()
+    };
+    let mut statements = split_statements(code).into_iter();
+    for statement in statements.by_ref() {
+        let tokens: Vec<&str> = statement.split_whitespace().collect();
+        let end = tokens.iter().position(|token| *token == "endmacro");
+        let command = match end {
+            Some(end) => tokens[..end].join(" "),
+            None => statement.trim().to_string(),
+        };
+        if !command.is_empty() {
+            definition.body.push(command);
+        }
+        if let Some(end) = end {
+            if end + 1 < tokens.len() || statements.any(|rest| !rest.trim().is_empty()) {
+                return Err(Error::WrongSyntax {
+                    expected: "nothing after endmacro".to_string(),
+                });
+            }
+            let (name, definition) = scope.defining.take().unwrap();
+            scope.macros.insert(name, definition);
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+//every character of the code, with whether it belongs to a string literal, quotes included
+fn scan_strings(code: &str) -> impl Iterator<Item = (usize, char, bool)> + '_ {
+    let mut in_string = false;
+    let mut escaped = false;
+    code.char_indices().map(move |(i, c)| {
+        let quoted = in_string || c == '"';
+        if !in_string {
+            in_string = c == '"';
+        } else if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            in_string = false;
+        }
+        (i, c, quoted)
+    })
+}
+
+//the line without its comment, its whitespace collapsed outside of the string literals
+fn code_of_line(line: &str) -> String {
+    let mut code = String::new();
+    for (_, c, quoted) in scan_strings(line) {
+        if quoted {
+            code.push(c);
+        } else if c.is_whitespace() {
+            if !code.is_empty() && !code.ends_with(' ') {
+                code.push(' ');
+            }
+        } else if c == '/' && (code.is_empty() || code.ends_with([' ', '"'])) {
+            //a comment starts a token, or follows a string literal right away
+            break;
+        } else {
+            code.push(c);
+        }
+    }
+    code.truncate(code.trim_end().len());
+    code
+}
+
+//statements are separated by `;` outside of the string literals
+fn split_statements(code: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    for (i, c, quoted) in scan_strings(code) {
+        if c == ';' && !quoted {
+            statements.push(&code[start..i]);
+            start = i + 1;
+        }
+    }
+    statements.push(&code[start..]);
+    statements
+}
+
+//`push string "text"` builds the string the way the Jack compiler does
+fn push_string(literal: &str, commands: &mut Vec<Command>) -> Result<(), Error> {
+    let wrong_syntax = || Error::WrongSyntax {
+        expected: "push string \"<text>\" where text escapes \" and \\ and may use \\n, \\b \
+                   and \\x<two hexadecimal digits>"
+            .to_string(),
+    };
+    let text = literal
+        .trim()
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(wrong_syntax)?;
+    let mut characters = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let character = match c {
+            '\\' => match chars.next() {
+                Some('n') => NEW_LINE,
+                Some('b') => BACKSPACE,
+                Some(c @ ('"' | '\\')) => c as u16,
+                Some('x') => {
+                    let digits: String = chars.by_ref().take(2).collect();
+                    let code = u8::from_str_radix(&digits, 16)
+                        .ok()
+                        .filter(|_| digits.len() == 2)
+                        .ok_or_else(wrong_syntax)?;
+                    //the same characters as written out, with the keys past ~
+                    match code as u16 {
+                        code @ (32..=126 | NEW_LINE..=LAST_KEY) => code,
+                        _ => {
+                            return Err(Error::InvalidCharacter {
+                                character: code.into(),
+                            })
                         }
-                        "return" => {}
-                        _ => {}
                     }
                 }
+                _ => return Err(wrong_syntax()),
+            },
+            '"' => return Err(wrong_syntax()),
+            ' '..='~' => c as u16,
+            character => return Err(Error::InvalidCharacter { character }),
+        };
+        characters.push(character);
+    }
+
+    let push_constant = |value: usize| Command::Push {
+        memory_segment: "constant".to_string(),
+        index: value as u16,
+    };
+    commands.push(push_constant(characters.len()));
+    commands.push(Command::Call {
+        name: "String.new".to_string(),
+        number_of_args: 1,
+    });
+    for character in characters {
+        commands.push(push_constant(character.into()));
+        commands.push(Command::Call {
+            name: "String.appendChar".to_string(),
+            number_of_args: 2,
+        });
+    }
+    Ok(())
+}
+
+//the token with every word rewritten, the words being separated by the parentheses and commas
+//of a macro use
+fn map_words(token: &str, map: impl Fn(&str) -> String) -> String {
+    let mut mapped = String::new();
+    let mut rest = token;
+    while let Some(end) = rest.find(['(', ',', ')']) {
+        mapped += &map(&rest[..end]);
+        mapped += &rest[end..=end];
+        rest = &rest[end + 1..];
+    }
+    mapped + &map(rest)
+}
+
+//what every file of the program is parsed with
+struct Context<'a> {
+    project_constants: &'a Constants,
+    include_paths: &'a [String],
+    //the files being parsed, as found then canonicalized, from the outermost one
+    files: Vec<(String, PathBuf)>,
+    //number of macro expansions so far in the whole program, the labels being global
+    expansions: usize,
+    //every file included so far, canonicalized
+    included: HashSet<PathBuf>,
+}
+
+impl Context<'_> {
+    //the included file next to the file including it, or else in the first include path
+    //that has it
+    fn find(&self, path: &str) -> Result<PathBuf, Error> {
+        let path = Path::new(path);
+        let directory = self
+            .files
+            .last()
+            .and_then(|(file, _)| Path::new(file).parent());
+        directory
+            .into_iter()
+            .chain(self.include_paths.iter().map(Path::new))
+            .map(|directory| directory.join(path))
+            .chain(path.is_absolute().then(|| path.to_path_buf()))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| Error::IncludeNotFound {
+                path: path.display().to_string(),
+            })
+    }
+
+    fn parse_path(&mut self, path: &Path) -> Result<Vec<Command>, Error> {
+        let canonical = path.canonicalize().map_err(Error::Io)?;
+        let file = File::open(path).map_err(Error::Io)?;
+        self.files.push((path.display().to_string(), canonical));
+        let commands = self.parse(&mut BufReader::new(file));
+        self.files.pop();
+        commands
+    }
+
+    //`include "path.vm"`, the commands of the file spliced in place of the directive, its
+    //constants staying its own
+    fn include<'t>(
+        &mut self,
+        mut tokens: impl Iterator<Item = &'t str>,
+        line: usize,
+    ) -> Result<Vec<Command>, Error> {
+        let path = tokens
+            .next()
+            .and_then(|path| path.strip_prefix('"'))
+            .and_then(|path| path.strip_suffix('"'))
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| Error::WrongSyntax {
+                expected: "include \"<path>\"".to_string(),
+            })?;
+        let path = self.find(path)?;
+        let canonical = path.canonicalize().map_err(Error::Io)?;
+        if self.files.iter().any(|(_, file)| *file == canonical) {
+            return Err(Error::IncludeCycle {
+                path: path.display().to_string(),
+            });
+        }
+        //its functions are global, so it is spliced in once for the whole program
+        if !self.included.insert(canonical) {
+            return Ok(Vec::new());
+        }
+        self.parse_path(&path).map_err(|error| {
+            let inclusion = Inclusion {
+                file: self
+                    .files
+                    .last()
+                    .map_or("<input>".to_string(), |(file, _)| file.clone()),
+                line,
+            };
+            match error {
+                Error::Included {
+                    file,
+                    error,
+                    mut chain,
+                } => {
+                    chain.push(inclusion);
+                    Error::Included { file, error, chain }
+                }
+                error => Error::Included {
+                    file: path.display().to_string(),
+                    error: Box::new(error),
+                    chain: vec![inclusion],
+                },
+            }
+        })
+    }
+
+    //`macro NAME(a, b) body endmacro`, the body going on over the next lines until endmacro
+    fn define_macro(&self, header: &str, scope: &mut FileScope) -> Result<(), Error> {
+        let wrong_syntax = || Error::WrongSyntax {
+            expected: "macro <name>(<parameters>) <commands separated by ; or lines> endmacro \
+                       where name is not an instruction"
+                .to_string(),
+        };
+        //the body may use macros with parentheses of their own
+        let header = header.trim_start();
+        let name = header
+            .split(|c: char| c.is_whitespace() || c == '(')
+            .next()
+            .unwrap_or_default();
+        let rest = &header[name.len()..];
+        let (parameters, body) = match rest.trim_start().strip_prefix('(') {
+            Some(rest) => {
+                let (parameters, body) = rest.split_once(')').ok_or_else(wrong_syntax)?;
+                let parameters = parameters
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|parameter| !parameter.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                (parameters, body)
+            }
+            None => (Vec::new(), rest),
+        };
+        if !is_constant_name(name) || KEYWORDS.contains(&name) || INSTRUCTIONS.contains(&name) {
+            return Err(wrong_syntax());
+        }
+        if scope.macros.contains_key(name) {
+            return Err(Error::RedefinedMacro {
+                name: name.to_string(),
+            });
+        }
+        scope.defining = Some((
+            name.to_string(),
+            Macro {
+                parameters,
+                body: Vec::new(),
+            },
+        ));
+        read_macro_body(body, scope)
+    }
+
+    fn expand_macro(
+        &mut self,
+        statement: &str,
+        line_number: usize,
+        scope: &mut FileScope,
+        commands: &mut Vec<Command>,
+    ) -> Result<(), Error> {
+        //the name is the first token up to its parenthesis, like parse_statement finds it
+        let statement = statement.trim();
+        let name = statement
+            .split(|c: char| c.is_whitespace() || c == '(')
+            .next()
+            .unwrap_or_default();
+        let rest = statement[name.len()..].trim_start();
+        let arguments: Vec<&str> = match rest.strip_prefix('(') {
+            Some(arguments) => arguments
+                .strip_suffix(')')
+                .unwrap_or(arguments)
+                .split(',')
+                .map(str::trim)
+                .filter(|argument| !argument.is_empty())
+                .collect(),
+            None => rest.split_whitespace().collect(),
+        };
+        if scope.expanding.iter().any(|expanding| expanding == name) {
+            return Err(Error::RecursiveMacro {
+                name: name.to_string(),
+            });
+        }
+        let definition = scope
+            .macros
+            .get(name)
+            .ok_or_else(|| Error::UnknownInstruction {
+                instruction: name.to_string(),
+            })?
+            .clone();
+        if arguments.len() != definition.parameters.len() {
+            return Err(Error::MacroArguments {
+                name: name.to_string(),
+                expected: definition.parameters.len(),
+                found: arguments.len(),
+            });
+        }
+
+        //the labels of the body get a suffix of their own at every expansion, the ones coming
+        //from the arguments or defined outside of the macro are left alone
+        self.expansions += 1;
+        let suffix = format!("${}.{}", name, self.expansions);
+        let labels: Vec<&str> = definition
+            .body
+            .iter()
+            .filter_map(
+                |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["label", label] => Some(label),
+                    _ => None,
+                },
+            )
+            .filter(|label| {
+                !definition
+                    .parameters
+                    .iter()
+                    .any(|parameter| parameter == label)
+            })
+            .collect();
+
+        scope.expanding.push(name.to_string());
+        for line in &definition.body {
+            //nothing is substituted in the string literal of `push string`
+            let (line, literal) = line.split_at(line.find('"').unwrap_or(line.len()));
+            //body labels are renamed wherever they appear, arguments of other macros included
+            let mut tokens: Vec<String> = line
+                .split_whitespace()
+                .map(|token| {
+                    map_words(token, |word| {
+                        match definition
+                            .parameters
+                            .iter()
+                            .position(|parameter| parameter == word)
+                        {
+                            Some(i) => arguments[i].to_string(),
+                            None if labels.contains(&word) => format!("{}{}", word, suffix),
+                            None => word.to_string(),
+                        }
+                    })
+                })
+                .collect();
+            tokens.extend((!literal.is_empty()).then(|| literal.to_string()));
+            self.parse_statement(&tokens.join(" "), line_number, scope, commands)
+                .map_err(|error| Error::InMacro {
+                    name: name.to_string(),
+                    line: line_number,
+                    error: Box::new(error),
+                })?;
+        }
+        scope.expanding.pop();
+        Ok(())
+    }
+
+    fn parse_statement(
+        &mut self,
+        statement: &str,
+        line_number: usize,
+        scope: &mut FileScope,
+        commands: &mut Vec<Command>,
+    ) -> Result<(), Error> {
+        if let Some(literal) = statement.trim_start().strip_prefix("push string ") {
+            return push_string(literal, commands);
+        }
+        let mut tokens = statement.split_whitespace();
+        let Some(instruction) = tokens.next() else {
+            return Ok(());
+        };
+        match instruction {
+            "const" => define_constant(tokens, &mut scope.constants, self.project_constants)?,
+            "include" => commands.extend(self.include(tokens, line_number)?),
+            "endmacro" => {
+                return Err(Error::WrongSyntax {
+                    expected: "macro <name>(<parameters>) before endmacro".to_string(),
+                })
+            }
+            instruction
+                if scope
+                    .macros
+                    .contains_key(instruction.split('(').next().unwrap_or_default()) =>
+            {
+                self.expand_macro(statement, line_number, scope, commands)?
+            }
+            instruction => commands.push(parse_command(
+                instruction,
+                tokens,
+                &scope.constants,
+                self.project_constants,
+            )?),
+        }
+        Ok(())
+    }
+
+    fn parse<R: BufRead>(&mut self, reader: &mut R) -> Result<Vec<Command>, Error> {
+        let mut commands = Vec::new();
+        let mut scope = FileScope::default();
+        let mut line = String::new();
+        let mut line_number = 0;
+        while reader.read_line(&mut line).map_err(Error::Io)? > 0 {
+            line_number += 1;
+            if let Some(Command::Asm { instructions, .. }) = scope.assembling.as_mut() {
+                let instruction: String = line
+                    .split("//")
+                    .next()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .collect();
+                if instruction == "endasm" {
+                    commands.extend(scope.assembling.take());
+                } else if is_hack_instruction(&instruction) {
+                    instructions.push(instruction);
+                } else if !instruction.is_empty() {
+                    return Err(Error::InvalidAssembly { instruction });
+                }
+                line.clear();
+                continue;
+            }
+            let code = code_of_line(&line);
+            if scope.defining.is_some() {
+                read_macro_body(&code, &mut scope)?;
+            } else if let Some(header) = code.strip_prefix("macro ") {
+                self.define_macro(header, &mut scope)?;
+            } else if code.split_whitespace().next() == Some("asm") {
+                begin_asm(
+                    code.split_whitespace().skip(1),
+                    &mut scope,
+                    self.project_constants,
+                )?;
+            } else {
+                for statement in split_statements(&code) {
+                    self.parse_statement(statement, line_number, &mut scope, &mut commands)?;
+                }
+            }
+            line.clear();
+        }
+        if let Some((name, _)) = scope.defining {
+            return Err(Error::UnterminatedMacro { name });
+        }
+        if scope.assembling.is_some() {
+            return Err(Error::UnterminatedAsm);
+        }
+        Ok(commands)
+    }
+}
+
+//a file without project constants nor include paths, as the tests write them
+#[cfg(test)]
+pub fn parse_file<R: BufRead>(reader: &mut R) -> Result<Vec<Command>, Error> {
+    Context {
+        project_constants: &Constants::new(),
+        include_paths: &[],
+        files: Vec::new(),
+        expansions: 0,
+        included: HashSet::new(),
+    }
+    .parse(reader)
+}
+
+//a .vm file holding the program, as the tests of the passes and the writer write them
+#[cfg(test)]
+pub fn vm_file(filename: &str, program: &str) -> VmFile {
+    VmFile {
+        filename: filename.to_string(),
+        commands: parse_file(&mut program.as_bytes()).unwrap(),
+    }
+}
+
+/// Reads a project wide constants file, made of `const` directives only.
+pub fn parse_constants_file(path: &str) -> Result<Constants, Error> {
+    let mut reader = BufReader::new(File::open(path).map_err(Error::Io)?);
+    let mut constants = Constants::new();
+    let mut line = String::new();
+    while reader.read_line(&mut line).map_err(Error::Io)? > 0 {
+        let mut tokens = line
+            .split_whitespace()
+            .take_while(|token| !token.starts_with('/'));
+        match tokens.next() {
+            Some("const") => define_constant(tokens, &mut constants, &Constants::new())?,
+            Some(_) => {
+                return Err(Error::WrongSyntax {
+                    expected: "only const directives in a constants file".to_string(),
+                })
             }
-            Err(e) => return Err(Error::Io(e)),
+            None => {}
         }
         line.clear();
     }
+    Ok(constants)
+}
 
-    Ok(())
+//the files parsed with a context of their own, with every file they include canonicalized
+fn parse_files(
+    paths: &[PathBuf],
+    constants: &Constants,
+    include_paths: &[String],
+) -> Result<(Vec<VmFile>, HashSet<PathBuf>), Error> {
+    let mut context = Context {
+        project_constants: constants,
+        include_paths,
+        files: Vec::new(),
+        expansions: 0,
+        included: HashSet::new(),
+    };
+    let files = paths
+        .iter()
+        .map(|path| {
+            Ok(VmFile {
+                filename: path.file_stem().unwrap().to_string_lossy().into_owned(),
+                commands: context.parse_path(path)?,
+            })
+        })
+        .collect::<Result<_, Error>>()?;
+    Ok((files, context.included))
+}
+
+/// A directory is parsed as one program made of all the .vm files it contains, the files
+/// they include are searched next to them then in the include paths. An included file is
+/// spliced in where it is first included only, a file of the directory included by another
+/// one not being translated on its own.
+pub fn parse_program(
+    path: &str,
+    constants: &Constants,
+    include_paths: &[String],
+) -> Result<Vec<VmFile>, Error> {
+    let path = Path::new(path);
+    let mut paths = if path.is_dir() {
+        path.read_dir()
+            .map_err(Error::Io)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::Io)?
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|extension| extension == "vm"))
+            .collect()
+    } else {
+        vec![path.to_path_buf()]
+    };
+    paths.sort();
+
+    let (files, included) = parse_files(&paths, constants, include_paths)?;
+    let mut roots = Vec::new();
+    for path in paths {
+        if !included.contains(&path.canonicalize().map_err(Error::Io)?) {
+            roots.push(path);
+        }
+    }
+    if roots.len() == files.len() {
+        return Ok(files);
+    }
+    //parsed again from the files nothing includes, which then splice in every other one
+    Ok(parse_files(&roots, constants, include_paths)?.0)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    //a file with the project constants but no include paths
+    fn parse(program: &str, project_constants: &Constants) -> Result<Vec<Command>, Error> {
+        Context {
+            project_constants,
+            include_paths: &[],
+            files: Vec::new(),
+            expansions: 0,
+            included: HashSet::new(),
+        }
+        .parse(&mut program.as_bytes())
+    }
+
+    //a fresh directory holding the files, by path relative to it
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("vm_translator_{}_{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        for (path, content) in files {
+            let path = directory.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn test_include_search_paths() -> Result<(), Error> {
+        let directory = write_files(
+            "include",
+            &[
+                (
+                    "Prog/Main.vm",
+                    "function Main.main 0\ninclude \"lib/Math.vm\"\nreturn",
+                ),
+                (
+                    "Prog/lib/Math.vm",
+                    "const ONE 1\npush constant ONE\ninclude \"Util.vm\"",
+                ),
+                (
+                    "shared/Util.vm",
+                    "push constant ONE // Math.vm's constant is its own",
+                ),
+            ],
+        );
+        let program = directory.join("Prog").display().to_string();
+        let include_paths = [directory.join("shared").display().to_string()];
+        let error = parse_program(&program, &Constants::new(), &include_paths).unwrap_err();
+        let Error::Included { error, chain, .. } = &error else {
+            panic!("{}", error);
+        };
+        assert!(matches!(**error, Error::UndefinedConstant { .. }));
+        assert_eq!(chain.len(), 2);
+
+        std::fs::write(directory.join("shared/Util.vm"), "push constant TWO").unwrap();
+        let constants = Constants::from([("TWO".to_string(), 2)]);
+        let files = parse_program(&program, &constants, &include_paths)?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files[0].commands,
+            parse_file(
+                &mut "function Main.main 0\npush constant 1\npush constant 2\nreturn".as_bytes()
+            )?
+        );
+
+        let missing = parse_program(&program, &constants, &[]).unwrap_err();
+        assert!(missing.to_string().contains("not found: Util.vm"));
+        std::fs::remove_dir_all(directory).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_included_files_of_the_directory() -> Result<(), Error> {
+        let directory = write_files(
+            "included_sibling",
+            &[
+                (
+                    "Main.vm",
+                    "include \"Helpers.vm\"\nfunction Main.main 0\ncall Helpers.one 0\nreturn",
+                ),
+                (
+                    "Helpers.vm",
+                    "function Helpers.one 0\npush constant 1\nreturn",
+                ),
+                ("Other.vm", "function Other.two 0\npush constant 2\nreturn"),
+            ],
+        );
+        let files = parse_program(&directory.display().to_string(), &Constants::new(), &[])?;
+        let filenames: Vec<&str> = files.iter().map(|file| file.filename.as_str()).collect();
+        assert_eq!(filenames, ["Main", "Other"]);
+        assert_eq!(
+            files[0].commands,
+            parse_file(
+                &mut "function Helpers.one 0\npush constant 1\nreturn\n\
+                      function Main.main 0\ncall Helpers.one 0\nreturn"
+                    .as_bytes()
+            )?
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_included_twice() -> Result<(), Error> {
+        let directory = write_files(
+            "included_twice",
+            &[
+                ("A.vm", "include \"lib/Util.vm\"\nfunction A.a 0\nreturn"),
+                (
+                    "Helpers.vm",
+                    "include \"lib/Util.vm\"\nfunction Helpers.one 0\nreturn",
+                ),
+                (
+                    "Main.vm",
+                    "include \"Helpers.vm\"\nfunction Main.main 0\nreturn",
+                ),
+                ("lib/Util.vm", "function Util.one 0\nreturn"),
+            ],
+        );
+        let files = parse_program(&directory.display().to_string(), &Constants::new(), &[])?;
+        let functions: Vec<(&str, &str)> = files
+            .iter()
+            .flat_map(|file| {
+                file.commands.iter().filter_map(|command| match command {
+                    Command::Function { name, .. } => Some((file.filename.as_str(), name.as_str())),
+                    _ => None,
+                })
+            })
+            .collect();
+        assert_eq!(
+            functions,
+            [
+                ("A", "Util.one"),
+                ("A", "A.a"),
+                ("Main", "Helpers.one"),
+                ("Main", "Main.main")
+            ]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_macros() -> Result<(), Error> {
+        let commands = parse(
+            "macro inc(seg, i) push seg i; push constant 1; add; pop seg i endmacro\n\
+             macro countdown(seg, i, done)\n\
+             label LOOP\npush seg i\nif-goto BODY\ngoto done\n\
+             label BODY\ninc seg i // macros use the macros defined before them\ngoto LOOP\n\
+             endmacro\n\
+             function Main.main 1\ninc local 0\ncountdown(argument, 1, END)\n\
+             countdown this 2 END\nlabel END",
+            &Constants::new(),
+        )?;
+        let body = |seg: &str, i: u16, n: usize| {
+            format!(
+                "label LOOP$countdown.{n}\npush {seg} {i}\nif-goto BODY$countdown.{n}\ngoto END\n\
+                 label BODY$countdown.{n}\npush {seg} {i}\npush constant 1\nadd\npop {seg} {i}\n\
+                 goto LOOP$countdown.{n}\n"
+            )
+        };
+        let expanded = format!(
+            "function Main.main 1\npush local 0\npush constant 1\nadd\npop local 0\n{}{}label END",
+            body("argument", 1, 2),
+            body("this", 2, 4)
+        );
+        assert_eq!(commands, parse_file(&mut expanded.as_bytes())?);
+
+        //labels handed over to another macro are renamed too
+        let commands = parse(
+            "macro jump(dest) goto dest endmacro\n\
+             macro spin label TOP; jump TOP; jump(TOP) endmacro\nspin",
+            &Constants::new(),
+        )?;
+        assert_eq!(
+            commands,
+            parse_file(&mut "label TOP$spin.1\ngoto TOP$spin.1\ngoto TOP$spin.1".as_bytes())?
+        );
+
+        for (program, expected) in [
+            (
+                "macro two(a, b) push a b endmacro\n\ntwo constant",
+                "macro two takes 2 arguments but 1 were given",
+            ),
+            (
+                "macro p(seg) push seg 0 endmacro\np nowhere",
+                "unknown memory segment: nowhere\n  in macro p used at line 2",
+            ),
+            (
+                "macro r() push constant 0; r endmacro\nr",
+                "macro r expands into itself\n  in macro r used at line 2",
+            ),
+            ("macro m(a) push a 0\n", "macro m misses its endmacro"),
+            (
+                "macro m endmacro\nmacro m endmacro",
+                "macro defined twice: m",
+            ),
+            ("macro add(a) endmacro", "wrong syntax"),
+            (
+                "macro m endmacro\nm x(y)",
+                "macro m takes 0 arguments but 1 were given",
+            ),
+        ] {
+            let error = parse(program, &Constants::new()).unwrap_err();
+            assert_eq!(error.to_string().split(", expected").next(), Some(expected));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_macro_labels_are_unique_across_files() -> Result<(), Error> {
+        let spin = "macro spin label TOP; goto TOP endmacro\n";
+        let directory = write_files(
+            "macro_labels",
+            &[
+                ("A.vm", &format!("{}function A.a 0\nspin", spin)),
+                ("B.vm", &format!("{}function B.b 0\nspin", spin)),
+            ],
+        );
+        let files = parse_program(&directory.display().to_string(), &Constants::new(), &[])?;
+        let labels: Vec<&Command> = files
+            .iter()
+            .flat_map(|file| &file.commands)
+            .filter(|command| matches!(command, Command::Label(_)))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                &Command::Label("TOP$spin.1".to_string()),
+                &Command::Label("TOP$spin.2".to_string())
+            ]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_asm_blocks() -> Result<(), Error> {
+        let commands = parse(
+            "asm\n@SCREEN // a comment\nD = M ; JGT\n(LOOP.1)\nAMD=D|M;JMP\nendasm\n\
+             asm 2 1\n@32767\nendasm",
+            &Constants::new(),
+        )?;
+        assert_eq!(
+            commands,
+            [
+                Command::Asm {
+                    instructions: ["@SCREEN", "D=M;JGT", "(LOOP.1)", "AMD=D|M;JMP"]
+                        .map(str::to_string)
+                        .to_vec(),
+                    stack_effect: None,
+                },
+                Command::Asm {
+                    instructions: vec!["@32767".to_string()],
+                    stack_effect: Some((2, 1)),
+                }
+            ]
+        );
+        assert_eq!(commands[1].stack_effect(), Some((2, 1)));
+
+        for (program, expected) in [
+            ("asm\nD=M+D\nendasm", "invalid Hack instruction: D=M+D"),
+            ("asm\n@32768\nendasm", "invalid Hack instruction: @32768"),
+            ("asm\nMA=0\nendasm", "invalid Hack instruction: MA=0"),
+            ("asm\n0;JUMP\nendasm", "invalid Hack instruction: 0;JUMP"),
+            ("asm\n(1LOOP)\nendasm", "invalid Hack instruction: (1LOOP)"),
+            ("asm\n@R0", "asm block misses its endasm"),
+            (
+                "asm 1\nendasm",
+                "wrong syntax, expected: asm [<pops> <pushes>]",
+            ),
+        ] {
+            let error = parse(program, &Constants::new()).unwrap_err();
+            assert_eq!(error.to_string(), expected);
+        }
+        Ok(())
+    }
+
     #[test]
-    fn test_filename_exist() -> Result<(), Error> {
+    fn test_string_literals() -> Result<(), Error> {
+        let commands = parse(
+            "push string \"a; b //c\"// a comment\n\
+             macro greet(name) push string \"name\\n\" endmacro\ngreet x\n\
+             push string \"\\\"\\\\\\b\\x8C\"; push string \"\"",
+            &Constants::new(),
+        )?;
+        let built = |characters: &[u16]| {
+            let mut program = format!("push constant {}\ncall String.new 1\n", characters.len());
+            for character in characters {
+                program += &format!("push constant {character}\ncall String.appendChar 2\n");
+            }
+            program
+        };
+        let expected = [
+            built(&[97, 59, 32, 98, 32, 47, 47, 99]),
+            built(&[110, 97, 109, 101, 128]),
+            built(&[34, 92, 129, 140]),
+            built(&[]),
+        ]
+        .concat();
+        assert_eq!(commands, parse_file(&mut expected.as_bytes())?);
+
+        for (program, expected) in [
+            ("push string \"é\"", "'é' is not in the Hack character set"),
+            (
+                "push string \"\t\"",
+                "'\\t' is not in the Hack character set",
+            ),
+            ("push string \"\\q\"", "wrong syntax"),
+            ("push string \"\\x8\"", "wrong syntax"),
+            (
+                "push string \"\\x09\"",
+                "'\\t' is not in the Hack character set",
+            ),
+            (
+                "push string \"\\x7F\"",
+                "'\\u{7f}' is not in the Hack character set",
+            ),
+            (
+                "push string \"\\xFF\"",
+                "'ÿ' is not in the Hack character set",
+            ),
+            ("push string \"open", "wrong syntax"),
+            ("push string unquoted", "wrong syntax"),
+        ] {
+            let error = parse(program, &Constants::new()).unwrap_err();
+            assert_eq!(error.to_string().split(", expected").next(), Some(expected));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ram_addresses() -> Result<(), Error> {
+        let project_constants = Constants::from([("ROW".to_string(), 32)]);
+        let commands = parse(
+            "const KBD 7\npush ram SCREEN+32\npop ram KBD\npush ram R15\n\
+             push ram SCREEN+ROW-1+0x10\npop ram 24576\npush ram THAT",
+            &project_constants,
+        )?;
+        let addresses: Vec<u16> = commands
+            .iter()
+            .map(|command| match command {
+                Command::Push { index, .. } | Command::Pop { index, .. } => *index,
+                _ => unreachable!(),
+            })
+            .collect();
+        //the constants of the file come before the Hack symbols
+        assert_eq!(addresses, [16416, 7, 15, 16431, 24576, 4]);
+
+        for program in [
+            "push ram KBD+1",
+            "push ram 0-1",
+            "push ram SCREEN+",
+            "push ram -1",
+        ] {
+            let error = parse(program, &project_constants).unwrap_err();
+            assert!(error.to_string().starts_with("wrong syntax"), "{}", program);
+        }
+        for program in ["push ram SCREEN+WIDTH", "push ram R16"] {
+            assert!(matches!(
+                parse(program, &project_constants),
+                Err(Error::UndefinedConstant { .. })
+            ));
+        }
         Ok(())
     }
 
+    #[test]
+    fn test_include_cycle() {
+        let directory = write_files(
+            "include_cycle",
+            &[
+                ("Main.vm", "function Main.main 0\ninclude \"A.vm\""),
+                ("A.vm", "// A\ninclude \"B.vm\""),
+                ("B.vm", "include \"A.vm\""),
+            ],
+        );
+        let main = directory.join("Main.vm");
+        let error = parse_program(&main.display().to_string(), &Constants::new(), &[])
+            .unwrap_err()
+            .to_string();
+        let b = directory.join("B.vm").display().to_string();
+        let a = directory.join("A.vm").display().to_string();
+        assert_eq!(
+            error,
+            format!(
+                "{b}: {a} ends up including itself\n  included from {a}:2\n  included from {}:2",
+                main.display()
+            )
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
     #[test]
     fn test_unvalid_file_syntax() -> Result<(), Error> {
+        let project_constants = Constants::from([("SCREEN".to_string(), 16384)]);
+        for (program, expected) in [
+            ("push constant WIDTH", "undefined constant: WIDTH"),
+            ("const A 1\nconst A 2", "constant defined twice: A"),
+            ("const SCREEN 1", "constant defined twice: SCREEN"),
+            ("const 1A 2", "wrong syntax"),
+            ("const BIG 70000", "wrong syntax"),
+            ("const SMALL -32769", "wrong syntax"),
+            ("push local -1", "wrong syntax"),
+            ("push constant 0x10000", "wrong syntax"),
+            ("push constant 0b2", "wrong syntax"),
+            ("const A B", "undefined constant: B"),
+            ("push that 0x8000", "wrong syntax"),
+            ("pop this 33000", "wrong syntax"),
+            ("push local 0b1000000000000000", "wrong syntax"),
+            (
+                "pop static 240",
+                "wrong syntax, expected: pop static <i> where i must be a number between 0 and 239",
+            ),
+        ] {
+            let error = parse(program, &project_constants).unwrap_err();
+            assert!(
+                error.to_string().starts_with(expected),
+                "{}: {}",
+                program,
+                error
+            );
+        }
         Ok(())
     }
 
+    #[test]
+    fn test_fixed_segment_bounds() {
+        for program in [
+            "push temp 7",
+            "pop temp 0",
+            "push pointer 1",
+            "pop pointer 0",
+            "push that 32767",
+            "pop static 239",
+        ] {
+            assert!(parse_file(&mut program.as_bytes()).is_ok(), "{}", program);
+        }
+        for program in [
+            "push temp 8",
+            "pop temp 65535",
+            "push pointer 2",
+            "pop pointer 65535",
+        ] {
+            assert!(
+                matches!(
+                    parse_file(&mut program.as_bytes()),
+                    Err(Error::WrongSyntax { .. })
+                ),
+                "{}",
+                program
+            );
+        }
+    }
+
     #[test]
     fn test_valid_file_syntax() -> Result<(), Error> {
+        let project_constants = Constants::from([("SCREEN".to_string(), 16384)]);
+        let commands = parse(
+            "const ROW 32 // words per row\nconst LOCALS 2\nconst FIRST ROW\n\
+             function Main.main LOCALS\npush constant SCREEN\npop pointer 1\n\
+             push constant FIRST\npop that ROW\ncall Main.main LOCALS",
+            &project_constants,
+        )?;
+        assert_eq!(
+            commands,
+            parse_file(
+                &mut "function Main.main 2\npush constant 16384\npop pointer 1\n\
+                      push constant 32\npop that 32\ncall Main.main 2"
+                    .as_bytes()
+            )?
+        );
+
+        let literals = parse(
+            "const MASK 0x8000\npush constant -1\npush constant -32768\npush constant 0xFFFF\n\
+             push constant 0b101\npush constant 65535\npush constant MASK\npush local 0x10",
+            &Constants::new(),
+        )?;
+        let values: Vec<u16> = literals
+            .iter()
+            .map(|command| match command {
+                Command::Push { index, .. } => *index,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(values, [0xFFFF, 0x8000, 0xFFFF, 5, 0xFFFF, 0x8000, 16]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_constants_file() -> Result<(), Error> {
+        let directory = write_files(
+            "constants",
+            &[
+                (
+                    "project.const",
+                    "// sizes of the screen\n\n    const WIDTH 512\nconst ROW 32 // words\n\
+                     const LAST_ROW ROW\n",
+                ),
+                ("invalid.const", "const WIDTH 512\npush constant WIDTH\n"),
+            ],
+        );
+        let path = |name: &str| directory.join(name).display().to_string();
+        let constants = parse_constants_file(&path("project.const"))?;
+        assert_eq!(
+            constants,
+            Constants::from([
+                ("WIDTH".to_string(), 512),
+                ("ROW".to_string(), 32),
+                ("LAST_ROW".to_string(), 32),
+            ])
+        );
+        let error = parse_constants_file(&path("invalid.const")).unwrap_err();
+        assert!(
+            matches!(&error, Error::WrongSyntax { expected } if expected.contains("only const")),
+            "{}",
+            error
+        );
+        std::fs::remove_dir_all(directory).unwrap();
         Ok(())
     }
 }
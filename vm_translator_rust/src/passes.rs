@@ -0,0 +1,273 @@
+//! Pass manager: the optimizations rewriting the vm commands before the translation and the
+//! Hack instructions after it.
+//!
+//! The `-O` level picks the passes that run, `--pass` and `--no-pass` then add or remove
+//! single ones. Every pass reports the number of Hack instructions of the program before and
+//! after it ran; the vm passes are measured on the translation without the Hack passes so
+//! that each pass is only credited with its own savings.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use crate::options::Options;
+use crate::parser::VmFile;
+use crate::{
+    constant_folding, dead_function_elimination, inlining, jump_threading, peephole, writer,
+};
+
+pub const DEFAULT_OPT_LEVEL: u8 = 2;
+pub const MAX_OPT_LEVEL: u8 = 3;
+
+//declared in the order the passes run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pass {
+    Inline,
+    DeadFunctionElimination,
+    ConstantFolding,
+    JumpThreading,
+    Peephole,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 5] = [
+        Pass::Inline,
+        Pass::DeadFunctionElimination,
+        Pass::ConstantFolding,
+        Pass::JumpThreading,
+        Pass::Peephole,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::Inline => "inline",
+            Pass::DeadFunctionElimination => "dead-function-elimination",
+            Pass::ConstantFolding => "constant-folding",
+            Pass::JumpThreading => "jump-threading",
+            Pass::Peephole => "peephole",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::ALL.into_iter().find(|pass| pass.name() == name)
+    }
+
+    //lowest -O level the pass runs at
+    fn level(&self) -> u8 {
+        match self {
+            Pass::ConstantFolding | Pass::JumpThreading | Pass::Peephole => 1,
+            Pass::Inline | Pass::DeadFunctionElimination => 2,
+        }
+    }
+
+    fn rewrites_hack_instructions(&self) -> bool {
+        matches!(self, Pass::Peephole)
+    }
+}
+
+pub fn passes_of_level(level: u8) -> BTreeSet<Pass> {
+    Pass::ALL
+        .into_iter()
+        .filter(|pass| pass.level() <= level)
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Statistics {
+    pub pass: Pass,
+    pub before: usize,
+    pub after: usize,
+    pub duration: Duration,
+    /// What the pass did, when it did something worth reporting.
+    pub summary: Option<String>,
+    /// Listed in verbose mode only.
+    pub details: Vec<String>,
+}
+
+impl Statistics {
+    pub fn saved(&self) -> isize {
+        self.before as isize - self.after as isize
+    }
+}
+
+impl core::fmt::Display for Statistics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} instructions ({} saved) in {:?}",
+            self.pass.name(),
+            self.before,
+            self.after,
+            self.saved(),
+            self.duration
+        )?;
+        if let Some(summary) = &self.summary {
+            write!(f, ", {}", summary)?;
+        }
+        Ok(())
+    }
+}
+
+fn count_translated_instructions(files: &[VmFile], options: &Options) -> usize {
+    peephole::count_instructions(&writer::translate(files, options))
+}
+
+/// Runs the enabled passes over the vm commands, in order.
+pub fn run_vm_passes(files: &mut [VmFile], options: &Options) -> Vec<Statistics> {
+    let mut statistics = Vec::new();
+    let mut before = count_translated_instructions(files, options);
+    for &pass in options
+        .passes
+        .iter()
+        .filter(|pass| !pass.rewrites_hack_instructions())
+    {
+        let start = Instant::now();
+        let (summary, details) = match pass {
+            Pass::Inline => {
+                let inlined = inlining::inline(files, options);
+                let summary = format!("{} call sites inlined", inlined);
+                ((inlined > 0).then_some(summary), Vec::new())
+            }
+            Pass::DeadFunctionElimination => {
+                let dropped = dead_function_elimination::eliminate(files, &options.entry);
+                let summary = format!("{} functions dropped", dropped.len());
+                ((!dropped.is_empty()).then_some(summary), dropped)
+            }
+            Pass::ConstantFolding => {
+                for file in files.iter_mut() {
+                    constant_folding::fold(&mut file.commands);
+                }
+                (None, Vec::new())
+            }
+            Pass::JumpThreading => {
+                let threaded: usize = files
+                    .iter_mut()
+                    .map(|file| jump_threading::thread_jumps(&mut file.commands))
+                    .sum();
+                let summary = format!("{} jumps threaded or removed", threaded);
+                ((threaded > 0).then_some(summary), Vec::new())
+            }
+            Pass::Peephole => unreachable!(),
+        };
+        let duration = start.elapsed();
+        let after = count_translated_instructions(files, options);
+        statistics.push(Statistics {
+            pass,
+            before,
+            after,
+            duration,
+            summary,
+            details,
+        });
+        before = after;
+    }
+    statistics
+}
+
+/// Runs the enabled passes over the translated program, in order.
+pub fn run_hack_passes(instructions: &mut Vec<String>, options: &Options) -> Vec<Statistics> {
+    let mut statistics = Vec::new();
+    for &pass in options
+        .passes
+        .iter()
+        .filter(|pass| pass.rewrites_hack_instructions())
+    {
+        let start = Instant::now();
+        let savings = match pass {
+            Pass::Peephole => peephole::optimize(instructions),
+            _ => unreachable!(),
+        };
+        statistics.push(Statistics {
+            pass,
+            before: savings.before,
+            after: savings.after,
+            duration: start.elapsed(),
+            summary: None,
+            details: Vec::new(),
+        });
+    }
+    statistics
+}
+
+//number of instructions the program translates to, without writing it anywhere
+pub fn count_hack_instructions(files: &[VmFile], options: &Options) -> usize {
+    let mut instructions = writer::translate(files, options);
+    run_hack_passes(&mut instructions, options);
+    peephole::count_instructions(&instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::vm_file;
+
+    #[test]
+    fn test_levels() {
+        assert!(passes_of_level(0).is_empty());
+        assert_eq!(
+            passes_of_level(1),
+            BTreeSet::from([Pass::ConstantFolding, Pass::JumpThreading, Pass::Peephole])
+        );
+        assert_eq!(passes_of_level(MAX_OPT_LEVEL), BTreeSet::from(Pass::ALL));
+        assert_eq!(
+            Pass::from_name("dead-function-elimination"),
+            Some(Pass::DeadFunctionElimination)
+        );
+        assert_eq!(Pass::from_name("unknown"), None);
+    }
+
+    #[test]
+    fn test_statistics_follow_the_program() {
+        let mut files = [vm_file(
+            "Main",
+            "function Sys.init 0\npush constant 1\npush constant 2\nadd\n\
+             push constant 0\nif-goto SKIP\nlabel SKIP\ncall Main.one 0\nreturn\n\
+             function Main.one 0\npush constant 1\nreturn\nfunction Main.unused 0\nreturn",
+        )];
+        let options = Options::default();
+        let unoptimized = count_translated_instructions(&files, &options);
+        let statistics = run_vm_passes(&mut files, &options);
+
+        let passes: Vec<Pass> = statistics
+            .iter()
+            .map(|statistics| statistics.pass)
+            .collect();
+        assert_eq!(
+            passes,
+            [
+                Pass::Inline,
+                Pass::DeadFunctionElimination,
+                Pass::ConstantFolding,
+                Pass::JumpThreading
+            ]
+        );
+        assert_eq!(statistics[0].before, unoptimized);
+        for (previous, next) in statistics.iter().zip(&statistics[1..]) {
+            assert_eq!(previous.after, next.before);
+        }
+        assert!(statistics.iter().all(|statistics| statistics.saved() > 0));
+        assert_eq!(statistics[1].details, ["Main.one", "Main.unused"]);
+
+        let mut instructions = writer::translate(&files, &options);
+        let statistics = run_hack_passes(&mut instructions, &options);
+        assert_eq!(
+            statistics[0].after,
+            peephole::count_instructions(&instructions)
+        );
+    }
+
+    #[test]
+    fn test_disabled_passes_do_not_run() {
+        let program = "function Sys.init 0\npush constant 1\npush constant 2\nadd\nreturn";
+        let mut files = [vm_file("Main", program)];
+        let options = Options {
+            passes: BTreeSet::new(),
+            ..Options::default()
+        };
+        assert!(run_vm_passes(&mut files, &options).is_empty());
+        assert_eq!(files[0], vm_file("Main", program));
+        let mut instructions = writer::translate(&files, &options);
+        let unoptimized = instructions.clone();
+        assert!(run_hack_passes(&mut instructions, &options).is_empty());
+        assert_eq!(instructions, unoptimized);
+    }
+}
@@ -0,0 +1,216 @@
+//! Peephole optimizer over the emitted Hack instruction stream.
+//!
+//! The writer emits one fixed template per vm instruction, so the seams between two
+//! templates are full of redundancies (a stack pointer incremented then decremented right
+//! away, `@SP` reloaded while A already holds it...). The rewrites below only ever look at
+//! consecutive instructions, a label `(xxx)` may be the target of a jump so it is never
+//! part of a pattern and always acts as a barrier.
+
+struct Rule {
+    pattern: &'static [&'static str],
+    replacement: &'static [&'static str],
+}
+
+const RULES: [Rule; 6] = [
+    // push followed by a pop: the stack pointer moves up then straight back down
+    Rule {
+        pattern: &["@SP", "M=M+1", "@SP", "M=M-1"],
+        replacement: &["@SP"],
+    },
+    Rule {
+        pattern: &["@SP", "M=M+1", "@SP", "AM=M-1"],
+        replacement: &["@SP", "A=M"],
+    },
+    Rule {
+        pattern: &["@SP", "M=M-1", "@SP", "M=M+1"],
+        replacement: &["@SP"],
+    },
+    // decrement the stack pointer and select the new top of the stack in one instruction
+    Rule {
+        pattern: &["@SP", "M=M-1", "A=M"],
+        replacement: &["@SP", "AM=M-1"],
+    },
+    // storing back the value that was just read (or reading back the value just stored)
+    Rule {
+        pattern: &["D=M", "M=D"],
+        replacement: &["D=M"],
+    },
+    Rule {
+        pattern: &["M=D", "D=M"],
+        replacement: &["M=D"],
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savings {
+    pub before: usize,
+    pub after: usize,
+}
+
+fn is_label(instruction: &str) -> bool {
+    instruction.starts_with('(')
+}
+
+fn is_address_instruction(instruction: &str) -> bool {
+    instruction.starts_with('@')
+}
+
+fn writes_address_register(instruction: &str) -> bool {
+    instruction
+        .split_once('=')
+        .is_some_and(|(dest, _)| dest.contains('A'))
+}
+
+fn is_unconditional_jump(instruction: &str) -> bool {
+    instruction.ends_with(";JMP")
+}
+
+pub fn count_instructions(instructions: &[String]) -> usize {
+    instructions.iter().filter(|line| !is_label(line)).count()
+}
+
+fn apply_rules(instructions: &mut Vec<String>) -> bool {
+    let mut optimized = Vec::with_capacity(instructions.len());
+    let mut changed = false;
+    let mut i = 0;
+
+    'instructions: while i < instructions.len() {
+        for rule in RULES.iter() {
+            let remaining = &instructions[i..];
+            if remaining.len() >= rule.pattern.len()
+                && rule
+                    .pattern
+                    .iter()
+                    .zip(remaining)
+                    .all(|(pattern, instruction)| pattern == instruction)
+            {
+                optimized.extend(rule.replacement.iter().map(|str| str.to_string()));
+                i += rule.pattern.len();
+                changed = true;
+                continue 'instructions;
+            }
+        }
+        optimized.push(std::mem::take(&mut instructions[i]));
+        i += 1;
+    }
+
+    *instructions = optimized;
+    changed
+}
+
+//an address loaded right before another one is never used, and reloading the address
+//the A register already holds is a no-op
+fn remove_redundant_address_loads(instructions: &mut Vec<String>) -> bool {
+    let len = instructions.len();
+    let mut address_register: Option<String> = None;
+    let mut optimized = Vec::with_capacity(len);
+
+    for i in 0..len {
+        let instruction = std::mem::take(&mut instructions[i]);
+        if is_label(&instruction) {
+            address_register = None;
+        } else if is_address_instruction(&instruction) {
+            let overwritten = instructions
+                .get(i + 1)
+                .is_some_and(|next| is_address_instruction(next));
+            if overwritten || address_register.as_deref() == Some(instruction.as_str()) {
+                continue;
+            }
+            address_register = Some(instruction.clone());
+        } else if writes_address_register(&instruction) {
+            address_register = None;
+        }
+        optimized.push(instruction);
+    }
+
+    let changed = optimized.len() != len;
+    *instructions = optimized;
+    changed
+}
+
+//nothing falls through an unconditional jump, only a label can be reached afterwards
+fn remove_unreachable_instructions(instructions: &mut Vec<String>) -> bool {
+    let len = instructions.len();
+    let mut reachable = true;
+
+    instructions.retain(|instruction| {
+        if is_label(instruction) {
+            reachable = true;
+        } else if !reachable {
+            return false;
+        } else if is_unconditional_jump(instruction) {
+            reachable = false;
+        }
+        true
+    });
+
+    instructions.len() != len
+}
+
+pub fn optimize(instructions: &mut Vec<String>) -> Savings {
+    let before = count_instructions(instructions);
+    loop {
+        let mut changed = apply_rules(instructions);
+        changed |= remove_redundant_address_loads(instructions);
+        changed |= remove_unreachable_instructions(instructions);
+        if !changed {
+            break;
+        }
+    }
+    Savings {
+        before,
+        after: count_instructions(instructions),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_instructions(asm: &str) -> Vec<String> {
+        asm.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_push_then_pop_cancels_stack_pointer_moves() {
+        let mut instructions =
+            to_instructions("@7 D=A @SP A=M M=D @SP M=M+1 @SP M=M-1 A=M D=M @THIS M=D");
+        let savings = optimize(&mut instructions);
+        assert_eq!(
+            instructions,
+            to_instructions("@7 D=A @SP A=M M=D @SP A=M D=M @THIS M=D")
+        );
+        assert_eq!(
+            savings,
+            Savings {
+                before: 13,
+                after: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_label_is_a_barrier() {
+        let asm = "@SP M=M+1 (LOOP) @SP M=M-1 @LOOP 0;JMP";
+        let mut instructions = to_instructions(asm);
+        let savings = optimize(&mut instructions);
+        assert_eq!(instructions, to_instructions(asm));
+        assert_eq!(
+            savings,
+            Savings {
+                before: 6,
+                after: 6
+            }
+        );
+    }
+
+    #[test]
+    fn test_redundant_and_unreachable_instructions() {
+        let mut instructions = to_instructions("@SP M=M+1 @SP D=M @R13 @END 0;JMP @SP M=0 (END)");
+        optimize(&mut instructions);
+        assert_eq!(
+            instructions,
+            to_instructions("@SP M=M+1 D=M @END 0;JMP (END)")
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Size of the translated program per function and per file, checked against the ROM.
+//!
+//! The instructions are attributed by walking the final program: a function's code starts at
+//! its `(name)` label and lasts until the next function, whatever the writer emitted before
+//! the first function is the bootstrap and the shared routines follow the end of program
+//! label.
+
+use std::collections::HashMap;
+
+use crate::parser::VmFile;
+use crate::writer::END_OF_PROGRAM;
+
+/// Number of instructions the Hack ROM holds.
+pub const ROM_SIZE: usize = 32768;
+const BOOTSTRAP: &str = "$bootstrap";
+const SHARED_ROUTINES: &str = "$shared-routines";
+//functions listed when the budget is exceeded
+const LARGEST_FUNCTIONS_IN_ERROR: usize = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Size {
+    pub name: String,
+    pub instructions: usize,
+}
+
+#[derive(Debug)]
+pub struct SizeReport {
+    pub total: usize,
+    /// Sorted from the largest to the smallest, the bootstrap and the shared routines are
+    /// reported as functions of their own.
+    pub functions: Vec<Size>,
+    /// Sorted from the largest to the smallest.
+    pub files: Vec<Size>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    RomBudgetExceeded {
+        budget: usize,
+        total: usize,
+        largest_functions: Vec<Size>,
+    },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::RomBudgetExceeded {
+                budget,
+                total,
+                largest_functions,
+            } => {
+                write!(
+                    f,
+                    "program is {} instructions, {} over the ROM budget of {}, largest functions:",
+                    total,
+                    total - budget,
+                    budget
+                )?;
+                for function in largest_functions {
+                    write!(f, " {} ({})", function.name, function.instructions)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn sorted(sizes: HashMap<&str, usize>) -> Vec<Size> {
+    let mut sizes: Vec<Size> = sizes
+        .into_iter()
+        .map(|(name, instructions)| Size {
+            name: name.to_string(),
+            instructions,
+        })
+        .collect();
+    sizes.sort_by(|a, b| {
+        b.instructions
+            .cmp(&a.instructions)
+            .then(a.name.cmp(&b.name))
+    });
+    sizes
+}
+
+impl SizeReport {
+    pub fn new(files: &[VmFile], instructions: &[String]) -> Self {
+        let file_of_function: HashMap<&str, &str> = files
+            .iter()
+            .flat_map(|file| {
+                file.functions()
+                    .map(move |(name, _)| (name, file.filename.as_str()))
+            })
+            .collect();
+
+        let mut functions: HashMap<&str, usize> = HashMap::new();
+        let mut current = BOOTSTRAP;
+        let mut total = 0;
+        for instruction in instructions {
+            if let Some(label) = instruction
+                .strip_prefix('(')
+                .and_then(|label| label.strip_suffix(')'))
+            {
+                if file_of_function.contains_key(label) {
+                    current = label;
+                } else if label == END_OF_PROGRAM {
+                    current = SHARED_ROUTINES;
+                }
+                continue;
+            }
+            *functions.entry(current).or_default() += 1;
+            total += 1;
+        }
+
+        let mut file_sizes: HashMap<&str, usize> = HashMap::new();
+        for (function, instructions) in &functions {
+            if let Some(file) = file_of_function.get(function) {
+                *file_sizes.entry(file).or_default() += instructions;
+            }
+        }
+
+        Self {
+            total,
+            functions: sorted(functions),
+            files: sorted(file_sizes),
+        }
+    }
+
+    pub fn check_budget(&self, budget: usize) -> Result<(), Error> {
+        if self.total <= budget {
+            return Ok(());
+        }
+        Err(Error::RomBudgetExceeded {
+            budget,
+            total: self.total,
+            largest_functions: self
+                .functions
+                .iter()
+                .take(LARGEST_FUNCTIONS_IN_ERROR)
+                .map(|function| Size {
+                    name: function.name.clone(),
+                    instructions: function.instructions,
+                })
+                .collect(),
+        })
+    }
+}
+
+impl core::fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "size: {} instructions, {:.1}% of the ROM",
+            self.total,
+            self.total as f64 * 100.0 / ROM_SIZE as f64
+        )?;
+        for (title, sizes) in [("functions", &self.functions), ("files", &self.files)] {
+            writeln!(f, "{}:", title)?;
+            for size in sizes {
+                writeln!(f, "  {:>6}  {}", size.instructions, size.name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{Optimization, Options};
+    use crate::parser::vm_file;
+    use crate::{passes, writer};
+
+    fn report(options: &Options) -> SizeReport {
+        let files = [
+            vm_file(
+                "Sys",
+                "function Sys.init 0\ncall Main.main 0\nlt\nlt\nreturn",
+            ),
+            vm_file(
+                "Main",
+                "function Main.main 0\npush constant 1\nreturn\n\
+                 function Main.small 0\nreturn",
+            ),
+        ];
+        let mut instructions = writer::translate(&files, options);
+        passes::run_hack_passes(&mut instructions, options);
+        SizeReport::new(&files, &instructions)
+    }
+
+    #[test]
+    fn test_sizes_add_up() {
+        let options = Options {
+            optimization: Optimization::Size,
+            ..Options::default()
+        };
+        let report = report(&options);
+        let names: Vec<&str> = report
+            .functions
+            .iter()
+            .map(|function| function.name.as_str())
+            .collect();
+        assert_eq!(names.len(), 5);
+        assert!(names.contains(&BOOTSTRAP) && names.contains(&SHARED_ROUTINES));
+        for sizes in [&report.functions, &report.files] {
+            assert!(sizes
+                .windows(2)
+                .all(|pair| pair[0].instructions >= pair[1].instructions));
+        }
+        let functions: usize = report.functions.iter().map(|size| size.instructions).sum();
+        assert_eq!(functions, report.total);
+        let size_of = |sizes: &[Size], name: &str| {
+            sizes
+                .iter()
+                .find(|size| size.name == name)
+                .unwrap()
+                .instructions
+        };
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(
+            size_of(&report.files, "Main"),
+            size_of(&report.functions, "Main.main") + size_of(&report.functions, "Main.small")
+        );
+        assert_eq!(
+            size_of(&report.files, "Sys"),
+            size_of(&report.functions, "Sys.init")
+        );
+    }
+
+    #[test]
+    fn test_budget() {
+        let report = report(&Options::default());
+        assert!(report.check_budget(ROM_SIZE).is_ok());
+        assert!(report.check_budget(report.total).is_ok());
+        let Err(Error::RomBudgetExceeded {
+            largest_functions, ..
+        }) = report.check_budget(10)
+        else {
+            panic!("budget not enforced");
+        };
+        assert_eq!(largest_functions[0], report.functions[0]);
+    }
+}
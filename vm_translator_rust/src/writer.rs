@@ -1,63 +1,145 @@
+//! Translation of the parsed vm commands into Hack assembly.
+//!
+//! Number of instructions of `push` and `pop` per segment and index, after the peephole
+//! pass and without top of stack caching (which saves the 5 instructions pushing D):
+//!
+//! | segment                     | index     | push | pop       |
+//! |-----------------------------|-----------|------|-----------|
+//! | constant                    | 0, 1, -1  | 6    | -         |
+//! | constant                    | others    | 7    | -         |
+//! | temp, pointer, static, ram  | any       | 7    | 5         |
+//! | local, argument, this, that | 0, 1      | 8    | 6         |
+//! | local, argument, this, that | 2         | 9    | 7         |
+//! | local, argument, this, that | 3..=8     | 10   | 5 + index |
+//! | local, argument, this, that | 9..       | 10   | 14        |
+//!
+//! An `asm ... endasm` block is copied as it is, once the top of the stack cached in D is
+//! back on the stack. The block may clobber A, D and R13 to R15, must leave LCL, ARG, THIS
+//! and THAT as it found them, must move SP by the stack effect it declares with
+//! `asm <pops> <pushes>` and must leave through its last instruction. Its labels are global
+//! to the program, which is why the functions holding a block are never inlined.
+
 use std::{
-    collections::HashMap,
+    collections::BTreeSet,
     fmt::Write,
     fs::{File, OpenOptions},
-    io::{BufRead, BufReader, BufWriter},
-    num::ParseIntError,
-    path::{Path, PathBuf},
-    str::SplitWhitespace,
+    io::{BufWriter, Write as _},
+    path::PathBuf,
 };
 
 use crate::memory_segments::MEMORY_SEGMENTS;
+use crate::options::{Optimization, Options};
+use crate::parser::{Command, VmFile, MAX_CONSTANT};
 
 const DEFAULT_CAPACITY: usize = 100usize;
+//biggest offsets of local/argument/this/that reached by incrementing A from the base, which
+//is shorter than adding the offset to it
+const MAX_UNROLLED_PUSH_OFFSET: u16 = 2;
+const MAX_UNROLLED_POP_OFFSET: u16 = 8;
+//most locals a prologue zeroes one store at a time instead of in a loop, the loop is shorter
+//from four locals on
+const MAX_UNROLLED_LOCALS: usize = 8;
+const MAX_UNROLLED_LOCALS_SIZE: usize = 3;
 
 #[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
-    ParseIntError(ParseIntError),
 }
 
 struct FunctionFrame {
-    number_of_args: String,
     call: usize,
 }
 
 impl FunctionFrame {
-    fn new(number_of_args: String) -> Self {
-        FunctionFrame {
-            number_of_args,
-            call: 0,
+    fn new() -> Self {
+        FunctionFrame { call: 0 }
+    }
+}
+
+//scratch register holding the return address while a shared routine runs
+const ROUTINE_RETURN_ADDRESS: &str = "R13";
+//scratch registers of the templates storing D to a computed address
+const SCRATCH_VALUE: &str = "R13";
+const SCRATCH_ADDRESS: &str = "R14";
+//scratch registers handing the call over to the shared call routine
+const CALL_ARGUMENT_OFFSET: &str = "R14";
+const CALL_FUNCTION_ADDRESS: &str = "R15";
+//scratch register walking over the arguments the tail call routine moves down
+const TAIL_CALL_ARGUMENT: &str = "R13";
+//words of the frame a call pushes: return address, LCL, ARG, THIS, THAT
+const FRAME_SIZE: usize = 5;
+//bits in a Hack word, shifting by as much or more shifts every bit out
+const WORD_SIZE: usize = 16;
+const DIVISION_REMAINDER: &str = "$divide.remainder";
+pub const END_OF_PROGRAM: &str = "$end";
+
+//templates emitted once at the end of the program and jumped to, instead of being inlined,
+//when optimizing for size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SharedRoutine {
+    Compare(&'static str),
+    Call,
+    Return,
+    TailCall,
+    Multiply,
+    //leaves the quotient on the stack and the remainder in DIVISION_REMAINDER
+    Divide,
+    ShiftLeft,
+    ShiftRight { arithmetic: bool },
+}
+
+impl SharedRoutine {
+    fn label(&self) -> String {
+        match self {
+            SharedRoutine::Compare(hack_instruction) => format!("$compare.{}", hack_instruction),
+            SharedRoutine::Call => "$call".to_string(),
+            SharedRoutine::Return => "$return".to_string(),
+            SharedRoutine::TailCall => "$tailcall".to_string(),
+            SharedRoutine::Multiply => "$mul".to_string(),
+            SharedRoutine::Divide => "$divide".to_string(),
+            SharedRoutine::ShiftLeft => "$shl".to_string(),
+            SharedRoutine::ShiftRight { arithmetic: true } => "$shr".to_string(),
+            SharedRoutine::ShiftRight { arithmetic: false } => "$ushr".to_string(),
         }
     }
+
+    //labels and variables private to the routine, the assembler allocates the variables
+    fn local(&self, name: &str) -> String {
+        format!("{}.{}", self.label(), name)
+    }
 }
 
 struct Writer<'a> {
     hack_instruction: String,
     label_count: usize,
-    writer: BufWriter<File>,
-    reader: BufReader<File>,
     filename_without_extendion: &'a str,
     function_frames: FunctionFrame,
     current_function_executed: String,
+    options: &'a Options,
+    shared_routines: BTreeSet<SharedRoutine>,
+    top_of_stack_in_register_d: bool,
 }
 
 impl<'a> Writer<'a> {
-    fn new(reader: BufReader<File>, writer: BufWriter<File>, filename: &'a str) -> Self {
+    fn new(options: &'a Options) -> Self {
         Self {
             hack_instruction: String::with_capacity(DEFAULT_CAPACITY),
             label_count: 0,
-            writer,
-            reader,
-            filename_without_extendion: filename,
-            function_frames: FunctionFrame {
-                number_of_args: String::new(),
-                call: 0,
-            },
+            filename_without_extendion: "",
+            function_frames: FunctionFrame::new(),
             current_function_executed: String::new(),
+            options,
+            shared_routines: BTreeSet::new(),
+            top_of_stack_in_register_d: false,
         }
     }
 
+    fn generate_label(&mut self) -> String {
+        let label_name = format!("LABEL.{}", self.label_count);
+        self.label_count += 1;
+        label_name
+    }
+
     fn write_label(&mut self, label: &str) -> &mut Self {
         let _ = writeln!(self.hack_instruction, "({})", label);
         self
@@ -73,61 +155,189 @@ impl<'a> Writer<'a> {
         self
     }
 
+    //the return address must be in D, the frame layout is the one restore_caller_frame
+    //expects: return address, LCL, ARG, THIS, THAT
+    fn push_caller_frame(&mut self) -> &mut Self {
+        self.push_register_d_onto_stack()
+            .push_register_onto_stack("LCL")
+            .push_register_onto_stack("ARG")
+            .push_register_onto_stack("THIS")
+            .push_register_onto_stack("THAT")
+    }
+
+    //the distance between SP and the first argument must be in D
+    fn reposition_argument_pointer(&mut self) -> &mut Self {
+        self.load_address_register("SP")
+            .assign_value_to_selected_register("D", "M-D")
+            .load_address_register("ARG")
+            .assign_value_to_selected_register("M", "D")
+    }
+
     //check handle_return_instruction if you were to modify that function
-    fn handle_call_instruction(&mut self, mut splitted_instruction: SplitWhitespace) {
-        let function_name = splitted_instruction.next().unwrap();
+    fn handle_call_instruction(&mut self, function_name: &str, number_of_args: usize) {
         let return_address = format!(
             "{}$ret.{}",
             self.current_function_executed, self.function_frames.call
         );
-        let number_of_args = splitted_instruction
-            .next()
-            .unwrap()
-            .parse::<usize>()
-            .unwrap()
-            + 5;
-        self.write_label(&return_address)
-            .push_memory_segment_onto_stack("constant", &return_address)
-            .push_memory_segment_onto_stack("argument", "0")
-            .push_memory_segment_onto_stack("local", "0")
-            .push_memory_segment_onto_stack("this", "0")
-            .push_memory_segment_onto_stack("that", "0")
-            .load_address_register(&number_of_args.to_string())
+        let number_of_args = (number_of_args + 5).to_string();
+        match self.options.optimization {
+            Optimization::Speed => {
+                self.load_address_register(&return_address)
+                    .assign_value_to_selected_register("D", "A")
+                    .push_caller_frame()
+                    .load_address_register(&number_of_args)
+                    .assign_value_to_selected_register("D", "A")
+                    .reposition_argument_pointer()
+                    .jump_to_address(function_name);
+            }
+            Optimization::Size => {
+                self.shared_routines.insert(SharedRoutine::Call);
+                self.load_address_register(&number_of_args)
+                    .assign_value_to_selected_register("D", "A")
+                    .load_address_register(CALL_ARGUMENT_OFFSET)
+                    .assign_value_to_selected_register("M", "D")
+                    .load_address_register(function_name)
+                    .assign_value_to_selected_register("D", "A")
+                    .load_address_register(CALL_FUNCTION_ADDRESS)
+                    .assign_value_to_selected_register("M", "D")
+                    .load_address_register(&return_address)
+                    .assign_value_to_selected_register("D", "A")
+                    .jump_to_address(&SharedRoutine::Call.label());
+            }
+        }
+        self.write_label(&return_address);
+        self.function_frames.call += 1;
+    }
+
+    //`call f n` right before `return`: the callee takes over the frame of the current function
+    //and returns straight to its caller, so the recursion runs in constant stack space
+    fn handle_tail_call_instruction(&mut self, function_name: &str, number_of_args: usize) {
+        self.shared_routines.insert(SharedRoutine::TailCall);
+        self.load_address_register(&number_of_args.to_string())
             .assign_value_to_selected_register("D", "A")
-            .load_address_register("SP")
-            .assign_value_to_selected_register("D", "M-D")
-            .load_address_register("ARG")
+            .load_address_register(CALL_ARGUMENT_OFFSET)
             .assign_value_to_selected_register("M", "D")
-            .jump_to_address(function_name);
-        self.function_frames.call += 1;
+            .load_address_register(function_name)
+            .assign_value_to_selected_register("D", "A")
+            .load_address_register(CALL_FUNCTION_ADDRESS)
+            .assign_value_to_selected_register("M", "D")
+            .jump_to_address(&SharedRoutine::TailCall.label());
+    }
+
+    //A = SP + offset
+    fn load_above_stack_address_register(&mut self, offset: usize) -> &mut Self {
+        self.load_address_register("SP")
+            .load_pointee_address_into_address_register();
+        for _ in 0..offset {
+            self.assign_value_to_selected_register("A", "A+1");
+        }
+        self
     }
 
-    fn handle_function_instruction(&mut self, mut splitted_instruction: SplitWhitespace) {
-        let function_name = splitted_instruction.next().unwrap();
-        let number_of_local_variables = splitted_instruction.next().unwrap();
-        let loop_label = format!("LOOP_{}", function_name);
-        let end_loop_label = format!("END_LOOP_{}", function_name);
-        let i = "i";
+    //the number of arguments is in CALL_ARGUMENT_OFFSET and the callee in
+    //CALL_FUNCTION_ADDRESS. The frame of the current function is stashed above the stack, the
+    //arguments are moved down to ARG and the frame right after them, every copy going down in
+    //memory so the forward copies never overwrite a word before reading it. LCL is free once
+    //the frame is stashed and serves as the destination pointer.
+    fn write_tail_call_routine(&mut self) {
+        let arguments_label = format!("{}.arguments", SharedRoutine::TailCall.label());
+        let frame_label = format!("{}.frame", SharedRoutine::TailCall.label());
+        for word in 0..FRAME_SIZE {
+            self.load_address_register("LCL")
+                .assign_value_to_selected_register("D", "M")
+                .load_address_register(&(FRAME_SIZE - word).to_string())
+                .assign_value_to_selected_register("A", "D-A")
+                .set_register_d_to_value_in_pointee()
+                .load_above_stack_address_register(word)
+                .set_pointee_value_to_value_in_register_d();
+        }
         self.load_address_register("SP")
             .assign_value_to_selected_register("D", "M")
+            .load_address_register(CALL_ARGUMENT_OFFSET)
+            .assign_value_to_selected_register("D", "D-M")
+            .load_address_register(TAIL_CALL_ARGUMENT)
+            .set_pointee_value_to_value_in_register_d()
+            .load_address_register("ARG")
+            .set_register_d_to_value_in_pointee()
             .load_address_register("LCL")
-            .assign_value_to_selected_register("M", "D")
-            .load_address_register(number_of_local_variables)
-            .assign_value_to_selected_register("D", "A")
-            .load_address_register(&end_loop_label)
+            .set_pointee_value_to_value_in_register_d()
+            .write_label(&arguments_label)
+            .load_address_register(TAIL_CALL_ARGUMENT)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register("SP")
+            .assign_value_to_selected_register("D", "D-M")
+            .load_address_register(&frame_label)
             .write_jump_instruction(None, Some("D"), "JEQ")
-            .load_address_register(i)
-            .assign_value_to_selected_register("M", "D")
-            .write_label(&loop_label)
+            .load_address_register(TAIL_CALL_ARGUMENT)
+            .assign_value_to_selected_register("M", "M+1")
+            .assign_value_to_selected_register("A", "M-1")
+            .set_register_d_to_value_in_pointee()
+            .load_address_register("LCL")
+            .assign_value_to_selected_register("M", "M+1")
+            .assign_value_to_selected_register("A", "M-1")
+            .set_pointee_value_to_value_in_register_d()
+            .jump_to_address(&arguments_label)
+            .write_label(&frame_label);
+        for word in 0..FRAME_SIZE {
+            self.load_above_stack_address_register(word)
+                .set_register_d_to_value_in_pointee()
+                .load_address_register("LCL")
+                .assign_value_to_selected_register("M", "M+1")
+                .assign_value_to_selected_register("A", "M-1")
+                .set_pointee_value_to_value_in_register_d();
+        }
+        self.load_address_register("LCL")
+            .set_register_d_to_value_in_pointee()
+            .load_address_register("SP")
+            .set_pointee_value_to_value_in_register_d()
+            .load_address_register(CALL_FUNCTION_ADDRESS)
+            .load_pointee_address_into_address_register()
+            .write_jump_instruction(None, Some("0"), "JMP");
+    }
+
+    //LCL = SP then every local is pushed as a zero, with a store per local for a few of
+    //them and a loop counting down in D otherwise
+    fn handle_function_instruction(
+        &mut self,
+        function_name: &str,
+        number_of_local_variables: usize,
+    ) {
+        self.write_label(function_name)
             .load_address_register("SP")
-            .assign_value_to_selected_register("A", "M")
-            .assign_value_to_selected_register("M", "0")
-            .load_and_increment_stack_pointer()
-            .load_and_decrement_register_by_one(i)
             .assign_value_to_selected_register("D", "M")
-            .load_address_register(&loop_label)
-            .write_jump_instruction(None, Some("D"), "JGT")
-            .write_label(&end_loop_label);
+            .load_address_register("LCL")
+            .assign_value_to_selected_register("M", "D");
+        let max_unrolled_locals = match self.options.optimization {
+            Optimization::Speed => MAX_UNROLLED_LOCALS,
+            Optimization::Size => MAX_UNROLLED_LOCALS_SIZE,
+        };
+        match number_of_local_variables {
+            0 => {}
+            locals if locals <= max_unrolled_locals => {
+                self.assign_value_to_selected_register("A", "D")
+                    .assign_value_to_selected_register("M", "0");
+                for _ in 1..locals {
+                    self.assign_value_to_selected_register("A", "A+1")
+                        .assign_value_to_selected_register("M", "0");
+                }
+                self.assign_value_to_selected_register("D", "A+1")
+                    .load_address_register("SP")
+                    .assign_value_to_selected_register("M", "D");
+            }
+            locals => {
+                let loop_label = self.generate_label();
+                self.load_address_register(&locals.to_string())
+                    .assign_value_to_selected_register("D", "A")
+                    .write_label(&loop_label)
+                    .load_address_register("SP")
+                    .assign_value_to_selected_register("AM", "M+1")
+                    .assign_value_to_selected_register("A", "A-1")
+                    .assign_value_to_selected_register("M", "0")
+                    .assign_value_to_selected_register("D", "D-1")
+                    .load_address_register(&loop_label)
+                    .write_jump_instruction(None, Some("D"), "JGT");
+            }
+        }
 
         if self.current_function_executed != function_name {
             self.function_frames.call = 0;
@@ -138,12 +348,23 @@ impl<'a> Writer<'a> {
 
     fn restore_pointer(&mut self, memory_segments: &str) -> &mut Self {
         self.load_address_register("LCL")
-            .assign_value_to_selected_register("DM", "M-1")
+            .assign_value_to_selected_register("AM", "M-1")
+            .set_register_d_to_value_in_pointee()
             .load_address_register(memory_segments)
             .assign_value_to_selected_register("M", "D")
     }
 
     fn handle_return_instruction(&mut self) {
+        match self.options.optimization {
+            Optimization::Speed => self.restore_caller_frame(),
+            Optimization::Size => {
+                self.shared_routines.insert(SharedRoutine::Return);
+                self.jump_to_address(&SharedRoutine::Return.label());
+            }
+        }
+    }
+
+    fn restore_caller_frame(&mut self) {
         let return_address = "return_address";
         self.load_address_register("LCL")
             .assign_value_to_selected_register("D", "M")
@@ -156,15 +377,17 @@ impl<'a> Writer<'a> {
             .assign_value_to_selected_register("A", "M-1")
             .assign_value_to_selected_register("D", "M")
             .load_address_register("ARG")
-            .assign_value_to_selected_register("M", "D")
+            .load_pointee_value_into_address_register_and_set_register_d_value_into_pointee()
             .assign_value_to_selected_register("D", "A")
             .load_address_register("SP")
             .assign_value_to_selected_register("M", "D+1")
             .restore_pointer("THAT")
             .restore_pointer("THIS")
-            .restore_pointer("LCL")
             .restore_pointer("ARG")
-            .jump_to_address(return_address);
+            .restore_pointer("LCL")
+            .load_address_register(return_address)
+            .load_pointee_address_into_address_register()
+            .write_jump_instruction(None, Some("0"), "JMP");
     }
 
     fn assign_value_to_selected_register(
@@ -221,12 +444,6 @@ impl<'a> Writer<'a> {
         self
     }
 
-    fn load_and_decrement_register_by_one(&mut self, address: &str) -> &mut Self {
-        self.load_address_register(address)
-            .assign_value_to_selected_register("M", "M-1");
-        self
-    }
-
     fn load_pointee_value_into_address_register_and_set_pointee_value_into_register_d(
         &mut self,
     ) -> &mut Self {
@@ -255,13 +472,6 @@ impl<'a> Writer<'a> {
         self
     }
 
-    fn write_hack_instruction_to_file(&mut self) -> Result<(), Error> {
-        std::io::Write::write_all(&mut self.writer, self.hack_instruction.as_bytes())
-            .map_err(Error::Io)?;
-        self.hack_instruction.clear();
-        Ok(())
-    }
-
     fn jump_to_address(&mut self, address: &str) -> &mut Self {
         self.load_address_register(address)
             .write_jump_instruction(None, Some("0"), "JMP")
@@ -287,86 +497,574 @@ impl<'a> Writer<'a> {
             .assign_value_to_selected_register("M", hack_instruction);
     }
 
-    fn convert_compare_instruction_to_hack_instruction_set(&mut self, hack_instruction: &str) {
-        let label_name = format!("LABEL.{}", self.label_count);
+    //the return address is handed over in D, the routine saves it before clobbering D
+    fn call_shared_routine(&mut self, routine: SharedRoutine) -> &mut Self {
+        let return_address = self.generate_label();
+        self.shared_routines.insert(routine);
+        self.load_address_register(&return_address)
+            .assign_value_to_selected_register("D", "A")
+            .jump_to_address(&routine.label())
+            .write_label(&return_address)
+    }
+
+    fn write_shared_routines(&mut self) {
+        if self.shared_routines.is_empty() {
+            return;
+        }
+        //the program must never fall through into the routines
+        self.write_label(END_OF_PROGRAM)
+            .jump_to_address(END_OF_PROGRAM);
+        for routine in std::mem::take(&mut self.shared_routines) {
+            self.write_label(&routine.label());
+            match routine {
+                SharedRoutine::Compare(hack_instruction) => {
+                    let label_name = format!("{}.true", routine.label());
+                    self.load_address_register(ROUTINE_RETURN_ADDRESS)
+                        .assign_value_to_selected_register("M", "D")
+                        .write_compare_instruction(hack_instruction, &label_name)
+                        .return_from_shared_routine();
+                }
+                SharedRoutine::Call => {
+                    self.push_caller_frame()
+                        .load_address_register(CALL_ARGUMENT_OFFSET)
+                        .set_register_d_to_value_in_pointee()
+                        .reposition_argument_pointer()
+                        .load_address_register(CALL_FUNCTION_ADDRESS)
+                        .load_pointee_address_into_address_register()
+                        .write_jump_instruction(None, Some("0"), "JMP");
+                }
+                SharedRoutine::Return => self.restore_caller_frame(),
+                SharedRoutine::TailCall => self.write_tail_call_routine(),
+                SharedRoutine::Multiply => self.write_multiply_routine(),
+                SharedRoutine::Divide => self.write_divide_routine(),
+                SharedRoutine::ShiftLeft => self.write_shift_left_routine(),
+                SharedRoutine::ShiftRight { arithmetic } => {
+                    self.write_shift_right_routine(arithmetic)
+                }
+            }
+        }
+    }
+
+    //the arithmetic routines take x and y on the stack and the return address in D, they
+    //replace x with the result and pop y
+
+    fn save_return_address_and_pop_y(&mut self) -> &mut Self {
+        self.load_address_register(ROUTINE_RETURN_ADDRESS)
+            .assign_value_to_selected_register("M", "D")
+            .pop_into_register_d()
+    }
+
+    fn load_x_address_register(&mut self) -> &mut Self {
+        self.load_address_register("SP")
+            .assign_value_to_selected_register("A", "M-1")
+    }
+
+    //shift and add: x is doubled and the mask walks over the bits of y
+    fn write_multiply_routine(&mut self) {
+        let routine = SharedRoutine::Multiply;
+        let (y, x, mask) = (
+            routine.local("y"),
+            routine.local("x"),
+            routine.local("mask"),
+        );
+        let (r#loop, next) = (routine.local("loop"), routine.local("next"));
+        self.save_return_address_and_pop_y()
+            .load_address_register(&y)
+            .set_pointee_value_to_value_in_register_d()
+            .load_x_address_register()
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("M", "0")
+            .load_address_register(&x)
+            .set_pointee_value_to_value_in_register_d()
+            .load_address_register(&mask)
+            .assign_value_to_selected_register("M", "1")
+            .write_label(&r#loop)
+            .load_address_register(&y)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(&mask)
+            .assign_value_to_selected_register("D", "D&M")
+            .load_address_register(&next)
+            .write_jump_instruction(None, Some("D"), "JEQ")
+            .load_address_register(&x)
+            .set_register_d_to_value_in_pointee()
+            .load_x_address_register()
+            .assign_value_to_selected_register("M", "D+M")
+            .write_label(&next)
+            .load_address_register(&x)
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("M", "D+M")
+            .load_address_register(&mask)
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("MD", "D+M")
+            .load_address_register(&r#loop)
+            .write_jump_instruction(None, Some("D"), "JNE")
+            .return_from_shared_routine();
+    }
+
+    //jumps to label when remainder >= divisor, both taken as unsigned since the absolute
+    //value of -32768 doesn't fit a positive word, and to next otherwise
+    fn jump_if_unsigned_greater_or_equal(
+        &mut self,
+        remainder: &str,
+        divisor: &str,
+        label: &str,
+        next: &str,
+    ) -> &mut Self {
+        let remainder_high = SharedRoutine::Divide.local("remainder_high");
+        let difference = |writer: &mut Self| {
+            writer
+                .load_address_register(remainder)
+                .set_register_d_to_value_in_pointee()
+                .load_address_register(divisor)
+                .assign_value_to_selected_register("D", "D-M")
+                .load_address_register(next)
+                .write_jump_instruction(None, Some("D"), "JLT")
+                .jump_to_address(label);
+        };
+        self.load_address_register(remainder)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(&remainder_high)
+            .write_jump_instruction(None, Some("D"), "JLT")
+            .load_address_register(divisor)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(next)
+            .write_jump_instruction(None, Some("D"), "JLT");
+        difference(self);
+        self.write_label(&remainder_high)
+            .load_address_register(divisor)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(label)
+            .write_jump_instruction(None, Some("D"), "JGE");
+        difference(self);
+        self
+    }
+
+    //long division of the absolute values, one bit of the dividend at a time from the most
+    //significant one, the signs are applied at the end: the quotient truncates toward zero
+    //and the remainder has the sign of x. By zero the quotient is 0 and the remainder x.
+    fn write_divide_routine(&mut self) {
+        let routine = SharedRoutine::Divide;
+        let (dividend, divisor) = (routine.local("dividend"), routine.local("divisor"));
+        let (quotient, negative) = (routine.local("quotient"), routine.local("negative"));
+        let count = routine.local("count");
+        let labels = [
+            "by_zero",
+            "dividend_positive",
+            "divisor_positive",
+            "loop",
+            "shift",
+            "subtract",
+            "next",
+            "quotient_sign",
+            "store",
+        ]
+        .map(|label| routine.local(label));
+        let [by_zero, dividend_positive, divisor_positive, r#loop, shift, subtract, next, quotient_sign, store] =
+            &labels;
+        self.save_return_address_and_pop_y()
+            .load_address_register(&divisor)
+            .set_pointee_value_to_value_in_register_d()
+            .load_x_address_register()
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(DIVISION_REMAINDER)
+            .set_pointee_value_to_value_in_register_d()
+            .load_address_register(&dividend)
+            .set_pointee_value_to_value_in_register_d()
+            .load_address_register(&divisor)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(by_zero)
+            .write_jump_instruction(None, Some("D"), "JEQ")
+            .load_address_register(&negative)
+            .assign_value_to_selected_register("M", "0")
+            .load_address_register(&dividend)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(dividend_positive)
+            .write_jump_instruction(None, Some("D"), "JGE")
+            .load_address_register(&dividend)
+            .assign_value_to_selected_register("M", "-D")
+            .load_address_register(&negative)
+            .assign_value_to_selected_register("M", "!M")
+            .write_label(dividend_positive)
+            .load_address_register(&divisor)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(divisor_positive)
+            .write_jump_instruction(None, Some("D"), "JGE")
+            .load_address_register(&divisor)
+            .assign_value_to_selected_register("M", "-D")
+            .load_address_register(&negative)
+            .assign_value_to_selected_register("M", "!M")
+            .write_label(divisor_positive)
+            .load_address_register(DIVISION_REMAINDER)
+            .assign_value_to_selected_register("M", "0")
+            .load_address_register(&quotient)
+            .assign_value_to_selected_register("M", "0")
+            .load_address_register(&WORD_SIZE.to_string())
+            .assign_value_to_selected_register("D", "A")
+            .load_address_register(&count)
+            .set_pointee_value_to_value_in_register_d()
+            //remainder = remainder * 2 + the top bit of the dividend, shifted out of it
+            .write_label(r#loop)
+            .load_address_register(DIVISION_REMAINDER)
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("M", "D+M")
+            .load_address_register(&dividend)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(shift)
+            .write_jump_instruction(None, Some("D"), "JGE")
+            .load_address_register(DIVISION_REMAINDER)
+            .assign_value_to_selected_register("M", "M+1")
+            .write_label(shift)
+            .load_address_register(&dividend)
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("M", "D+M")
+            .load_address_register(&quotient)
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("M", "D+M")
+            .jump_if_unsigned_greater_or_equal(DIVISION_REMAINDER, &divisor, subtract, next)
+            .write_label(subtract)
+            .load_address_register(&divisor)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(DIVISION_REMAINDER)
+            .assign_value_to_selected_register("M", "M-D")
+            .load_address_register(&quotient)
+            .assign_value_to_selected_register("M", "M+1")
+            .write_label(next)
+            .load_address_register(&count)
+            .assign_value_to_selected_register("MD", "M-1")
+            .load_address_register(r#loop)
+            .write_jump_instruction(None, Some("D"), "JGT")
+            .load_address_register(&negative)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(quotient_sign)
+            .write_jump_instruction(None, Some("D"), "JEQ")
+            .load_address_register(&quotient)
+            .assign_value_to_selected_register("M", "-M")
+            .write_label(quotient_sign)
+            .load_x_address_register()
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(store)
+            .write_jump_instruction(None, Some("D"), "JGE")
+            .load_address_register(DIVISION_REMAINDER)
+            .assign_value_to_selected_register("M", "-M")
+            .write_label(store)
+            .load_address_register(&quotient)
+            .set_register_d_to_value_in_pointee()
+            .load_x_address_register()
+            .set_pointee_value_to_value_in_register_d()
+            .return_from_shared_routine()
+            .write_label(by_zero)
+            .load_x_address_register()
+            .assign_value_to_selected_register("M", "0")
+            .return_from_shared_routine();
+    }
+
+    //D = y, jumps to large when y is 16 or more, taken as unsigned
+    fn jump_if_shifting_every_bit_out(&mut self, large: &str) -> &mut Self {
+        let word_size = WORD_SIZE.to_string();
+        self.load_address_register(large)
+            .write_jump_instruction(None, Some("D"), "JLT")
+            .load_address_register(&word_size)
+            .assign_value_to_selected_register("D", "D-A")
+            .load_address_register(large)
+            .write_jump_instruction(None, Some("D"), "JGE")
+            .load_address_register(&word_size)
+            .assign_value_to_selected_register("D", "D+A")
+    }
+
+    //x is doubled y times
+    fn write_shift_left_routine(&mut self) {
+        let routine = SharedRoutine::ShiftLeft;
+        let count = routine.local("count");
+        let [r#loop, large, done] = ["loop", "large", "done"].map(|label| routine.local(label));
+        self.save_return_address_and_pop_y()
+            .jump_if_shifting_every_bit_out(&large)
+            .write_label(&r#loop)
+            .load_address_register(&done)
+            .write_jump_instruction(None, Some("D"), "JEQ")
+            .load_address_register(&count)
+            .set_pointee_value_to_value_in_register_d()
+            .load_x_address_register()
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("M", "D+M")
+            .load_address_register(&count)
+            .assign_value_to_selected_register("D", "M-1")
+            .jump_to_address(&r#loop)
+            .write_label(&large)
+            .load_x_address_register()
+            .assign_value_to_selected_register("M", "0")
+            .write_label(&done)
+            .return_from_shared_routine();
+    }
+
+    //Hack can only shift left, so the bits of x from the y-th one are copied one at a time:
+    //the source mask starts at 1 << y, the destination one at 1. The arithmetic shift then
+    //fills the bits left of the last copied one with the sign, and shifts by 15 at most.
+    fn write_shift_right_routine(&mut self, arithmetic: bool) {
+        let routine = SharedRoutine::ShiftRight { arithmetic };
+        let [count, source, destination, result] =
+            ["count", "source", "destination", "result"].map(|name| routine.local(name));
+        let [shift, align, aligned, r#loop, next, store, large] = [
+            "shift", "align", "aligned", "loop", "next", "store", "large",
+        ]
+        .map(|label| routine.local(label));
+        self.save_return_address_and_pop_y()
+            .jump_if_shifting_every_bit_out(&large)
+            .write_label(&shift)
+            .load_address_register(&source)
+            .assign_value_to_selected_register("M", "1")
+            .write_label(&align)
+            .load_address_register(&aligned)
+            .write_jump_instruction(None, Some("D"), "JEQ")
+            .load_address_register(&count)
+            .set_pointee_value_to_value_in_register_d()
+            .load_address_register(&source)
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("M", "D+M")
+            .load_address_register(&count)
+            .assign_value_to_selected_register("D", "M-1")
+            .jump_to_address(&align)
+            .write_label(&aligned)
+            .load_address_register(&destination)
+            .assign_value_to_selected_register("M", "1")
+            .load_address_register(&result)
+            .assign_value_to_selected_register("M", "0")
+            .write_label(&r#loop)
+            .load_x_address_register()
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(&source)
+            .assign_value_to_selected_register("D", "D&M")
+            .load_address_register(&next)
+            .write_jump_instruction(None, Some("D"), "JEQ")
+            .load_address_register(&destination)
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(&result)
+            .assign_value_to_selected_register("M", "D|M")
+            .write_label(&next)
+            .load_address_register(&destination)
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("M", "D+M")
+            .load_address_register(&source)
+            .set_register_d_to_value_in_pointee()
+            .assign_value_to_selected_register("MD", "D+M")
+            .load_address_register(&r#loop)
+            .write_jump_instruction(None, Some("D"), "JNE");
+        if arithmetic {
+            //the destination mask is 1 << (16 - y), its negation has every bit from there on
+            self.load_x_address_register()
+                .set_register_d_to_value_in_pointee()
+                .load_address_register(&store)
+                .write_jump_instruction(None, Some("D"), "JGE")
+                .load_address_register(&destination)
+                .assign_value_to_selected_register("D", "-M")
+                .load_address_register(&result)
+                .assign_value_to_selected_register("M", "D|M")
+                .write_label(&store);
+        }
+        self.load_address_register(&result)
+            .set_register_d_to_value_in_pointee()
+            .load_x_address_register()
+            .set_pointee_value_to_value_in_register_d()
+            .return_from_shared_routine()
+            .write_label(&large);
+        if arithmetic {
+            self.load_address_register(&(WORD_SIZE - 1).to_string())
+                .assign_value_to_selected_register("D", "A")
+                .jump_to_address(&shift);
+        } else {
+            self.load_x_address_register()
+                .assign_value_to_selected_register("M", "0")
+                .return_from_shared_routine();
+        }
+    }
+
+    fn return_from_shared_routine(&mut self) -> &mut Self {
+        self.load_address_register(ROUTINE_RETURN_ADDRESS)
+            .load_pointee_address_into_address_register()
+            .write_jump_instruction(None, Some("0"), "JMP")
+    }
+
+    fn convert_compare_instruction_to_hack_instruction_set(
+        &mut self,
+        hack_instruction: &'static str,
+    ) {
+        match self.options.optimization {
+            Optimization::Speed => {
+                let label_name = self.generate_label();
+                self.write_compare_instruction(hack_instruction, &label_name);
+            }
+            Optimization::Size => {
+                self.call_shared_routine(SharedRoutine::Compare(hack_instruction));
+            }
+        }
+    }
+
+    //x - y overflows when x and y have opposite signs, the order is then given by the signs
+    //alone: D is set to 1 when x >= 0 > y and to -1 when x < 0 <= y, to x - y otherwise
+    fn write_overflow_safe_difference(&mut self, label_name: &str) -> &mut Self {
+        let x_negative = format!("{}.x_negative", label_name);
+        let subtract = format!("{}.subtract", label_name);
+        let compare = format!("{}.compare", label_name);
         self.load_and_decrement_stack_pointer()
+            .decrement_address_register_by_pointee_value_minus_one()
+            .set_register_d_to_value_in_pointee()
+            .load_address_register(&x_negative)
+            .write_jump_instruction(None, Some("D"), "JLT")
+            .load_address_register("SP")
             .load_pointee_value_into_address_register_and_set_pointee_value_into_register_d()
+            .load_address_register(&subtract)
+            .write_jump_instruction(None, Some("D"), "JGE")
+            .assign_value_to_selected_register("D", "1")
+            .jump_to_address(&compare)
+            .write_label(&x_negative)
             .load_address_register("SP")
-            .decrement_address_register_by_pointee_value_minus_one()
+            .load_pointee_value_into_address_register_and_set_pointee_value_into_register_d()
+            .load_address_register(&subtract)
+            .write_jump_instruction(None, Some("D"), "JLT")
+            .assign_value_to_selected_register("D", "-1")
+            .jump_to_address(&compare)
+            .write_label(&subtract)
+            .load_address_register("SP")
+            .load_pointee_value_into_address_register_and_set_pointee_value_into_register_d()
+            .assign_value_to_selected_register("A", "A-1")
             .assign_value_to_selected_register("D", "M-D")
-            .assign_value_to_selected_register("M", "-1")
-            .load_address_register(&label_name)
+            .write_label(&compare)
+            .load_address_register("SP")
+            .decrement_address_register_by_pointee_value_minus_one()
+    }
+
+    fn write_compare_instruction(&mut self, hack_instruction: &str, label_name: &str) -> &mut Self {
+        if self.options.safe_compare && is_ordering(hack_instruction) {
+            self.write_overflow_safe_difference(label_name);
+        } else {
+            self.load_and_decrement_stack_pointer()
+                .load_pointee_value_into_address_register_and_set_pointee_value_into_register_d()
+                .load_address_register("SP")
+                .decrement_address_register_by_pointee_value_minus_one()
+                .assign_value_to_selected_register("D", "M-D");
+        }
+        self.assign_value_to_selected_register("M", "-1")
+            .load_address_register(label_name)
             .write_jump_instruction(None, Some("D"), hack_instruction)
             .load_address_register("SP")
             .decrement_address_register_by_pointee_value_minus_one()
             .assign_value_to_selected_register("M", "0")
-            .write_label(&label_name);
-        self.label_count += 1;
+            .write_label(label_name)
+    }
+
+    fn push_register_d_onto_stack(&mut self) -> &mut Self {
+        self.load_address_register("SP")
+            .load_pointee_value_into_address_register_and_set_register_d_value_into_pointee()
+            .load_and_increment_stack_pointer()
+    }
+
+    fn push_register_onto_stack(&mut self, register: &str) -> &mut Self {
+        self.load_address_register(register)
+            .set_register_d_to_value_in_pointee()
+            .push_register_d_onto_stack()
     }
 
     fn push_memory_segment_onto_stack(&mut self, memory_segments: &str, offset: &str) -> &mut Self {
-        let remaning_instruction = |writer: &mut Self| {
-            writer
-                .load_address_register("SP")
-                .load_pointee_value_into_address_register_and_set_register_d_value_into_pointee()
-                .load_and_increment_stack_pointer();
-        };
+        self.load_memory_segment_into_register_d(memory_segments, offset)
+            .push_register_d_onto_stack()
+    }
 
-        match memory_segments {
-            "local" | "argument" | "this" | "that" => {
-                let addr = MEMORY_SEGMENTS.get(memory_segments).unwrap();
-                self.load_address_register(offset)
-                    .assign_value_to_selected_register("D", "A")
-                    .load_address_register(addr)
-                    .assign_value_to_selected_register("A", "D+M")
-                    .assign_value_to_selected_register("D", "M");
-                remaning_instruction(self);
+    //A = segment base + offset, walking A up from the base for the small offsets
+    fn load_segment_element_address_into_address_register(
+        &mut self,
+        memory_segments: &str,
+        offset: u16,
+    ) -> &mut Self {
+        let addr = MEMORY_SEGMENTS.get(memory_segments).unwrap();
+        self.load_address_register(addr);
+        if offset == 0 {
+            self.load_pointee_address_into_address_register();
+        } else {
+            self.increment_address_register_by_pointee_value_plus_one();
+            for _ in 1..offset {
+                self.assign_value_to_selected_register("A", "A+1");
             }
+        }
+        self
+    }
+
+    //temp lives at the fixed addresses 5 to 12
+    fn load_temp_address_register(&mut self, offset: &str) -> &mut Self {
+        let addr: u16 = MEMORY_SEGMENTS.get("temp").unwrap().parse().unwrap();
+        self.load_address_register(&(addr + offset.parse::<u16>().unwrap()).to_string())
+    }
+
+    fn load_memory_segment_into_register_d(
+        &mut self,
+        memory_segments: &str,
+        offset: &str,
+    ) -> &mut Self {
+        match memory_segments {
+            "local" | "argument" | "this" | "that" => match offset.parse::<u16>() {
+                Ok(offset) if offset <= MAX_UNROLLED_PUSH_OFFSET => {
+                    self.load_segment_element_address_into_address_register(
+                        memory_segments,
+                        offset,
+                    )
+                    .set_register_d_to_value_in_pointee();
+                }
+                _ => {
+                    let addr = MEMORY_SEGMENTS.get(memory_segments).unwrap();
+                    self.load_address_register(offset)
+                        .assign_value_to_selected_register("D", "A")
+                        .load_address_register(addr)
+                        .assign_value_to_selected_register("A", "D+M")
+                        .assign_value_to_selected_register("D", "M");
+                }
+            },
             "temp" => {
-                let addr = MEMORY_SEGMENTS.get(memory_segments).unwrap();
-                self.load_address_register(offset)
-                    .assign_value_to_selected_register("D", "A")
-                    .load_address_register(addr)
-                    .assign_value_to_selected_register("A", "D+A")
+                self.load_temp_address_register(offset)
                     .assign_value_to_selected_register("D", "M");
-                remaning_instruction(self);
-            }
-            "constant" => {
-                self.load_address_register(offset)
-                    .assign_value_to_selected_register("D", "A");
-                remaning_instruction(self);
             }
+            //the values A can't load are built from their negation or their complement
+            "constant" => match offset.parse::<u16>().unwrap() {
+                0 | 1 => {
+                    self.assign_value_to_selected_register("D", offset);
+                }
+                u16::MAX => {
+                    self.assign_value_to_selected_register("D", "-1");
+                }
+                value if value <= MAX_CONSTANT => {
+                    self.load_address_register(offset)
+                        .assign_value_to_selected_register("D", "A");
+                }
+                value if value.wrapping_neg() <= MAX_CONSTANT => {
+                    self.load_address_register(&value.wrapping_neg().to_string())
+                        .assign_value_to_selected_register("D", "-A");
+                }
+                value => {
+                    self.load_address_register(&(!value).to_string())
+                        .assign_value_to_selected_register("D", "!A");
+                }
+            },
             "pointer" => {
                 let instruction = if offset == "0" { "THIS" } else { "THAT" };
                 self.load_address_register(instruction)
                     .assign_value_to_selected_register("D", "M");
-                remaning_instruction(self);
             }
             "static" => {
                 self.load_static_in_address_register(self.filename_without_extendion, offset)
                     .assign_value_to_selected_register("D", "M");
-                remaning_instruction(self);
+            }
+            "ram" => {
+                self.load_address_register(offset)
+                    .assign_value_to_selected_register("D", "M");
             }
             _ => unreachable!(),
         };
         self
     }
 
-    fn pop_off_memory_segment_of_stack(&mut self, mut instruction_arguments: SplitWhitespace) {
-        let memory_segments = instruction_arguments.next().unwrap();
-        let ram_address = instruction_arguments.next().unwrap();
-
-        let remaining_instruction = |writer: &mut Self| {
-            writer
-                .load_address_register("SP")
-                .load_pointee_value_into_address_register_and_set_register_d_value_into_pointee()
-                .load_and_decrement_stack_pointer()
-                .load_pointee_value_into_address_register_and_set_pointee_value_into_register_d()
-                .load_address_register("SP")
-                .increment_address_register_by_pointee_value_plus_one()
-                .load_pointee_value_into_address_register_and_set_register_d_value_into_pointee();
-        };
-
+    //for every segment but pointer and constant
+    fn load_memory_segment_address_into_register_d(
+        &mut self,
+        memory_segments: &str,
+        ram_address: &str,
+    ) -> &mut Self {
         match memory_segments {
             "local" | "argument" | "this" | "that" => {
                 let addr = MEMORY_SEGMENTS.get(memory_segments).unwrap();
@@ -374,7 +1072,6 @@ impl<'a> Writer<'a> {
                     .assign_value_to_selected_register("D", "M")
                     .load_address_register(ram_address)
                     .assign_value_to_selected_register("D", "D+A");
-                remaining_instruction(self);
             }
             "temp" => {
                 let addr = MEMORY_SEGMENTS.get(memory_segments).unwrap();
@@ -382,104 +1079,450 @@ impl<'a> Writer<'a> {
                     .assign_value_to_selected_register("D", "A")
                     .load_address_register(ram_address)
                     .assign_value_to_selected_register("D", "D+A");
-                remaining_instruction(self);
-            }
-            "pointer" => {
-                self.load_and_decrement_stack_pointer()
-                    .load_pointee_value_into_address_register_and_set_pointee_value_into_register_d(
-                    );
-                let addr = if ram_address == "0" { "THIS" } else { "THAT" };
-                self.load_address_register(addr)
-                    .assign_value_to_selected_register("M", "D");
             }
             "static" => {
                 self.load_static_in_address_register(self.filename_without_extendion, ram_address)
                     .assign_value_to_selected_register("D", "A");
-                remaining_instruction(self);
             }
             _ => unreachable!(),
         };
+        self
+    }
+
+    fn pop_off_memory_segment_of_stack(&mut self, memory_segments: &str, ram_address: &str) {
+        let has_fixed_address = matches!(memory_segments, "temp" | "pointer" | "static" | "ram")
+            || ram_address
+                .parse::<u16>()
+                .is_ok_and(|offset| offset <= MAX_UNROLLED_POP_OFFSET);
+        if has_fixed_address {
+            self.pop_into_register_d()
+                .store_register_d_into_memory_segment(memory_segments, ram_address);
+            return;
+        }
+
+        self.load_memory_segment_address_into_register_d(memory_segments, ram_address)
+            .load_address_register("SP")
+            .load_pointee_value_into_address_register_and_set_register_d_value_into_pointee()
+            .load_and_decrement_stack_pointer()
+            .load_pointee_value_into_address_register_and_set_pointee_value_into_register_d()
+            .load_address_register("SP")
+            .increment_address_register_by_pointee_value_plus_one()
+            .load_pointee_value_into_address_register_and_set_register_d_value_into_pointee();
     }
 
-    fn execution(&mut self) -> Result<(), Error> {
-        let mut line = String::new();
-        loop {
-            match self.reader.read_line(&mut line) {
-                Ok(0) => break,
-                Ok(_) if line.starts_with('/') => {}
-                Ok(_) => {
-                    let mut splitted_instruction = line.split_whitespace();
-                    if let Some(instruction) = splitted_instruction.next() {
-                        match instruction {
-                            "push" => {
-                                self.push_memory_segment_onto_stack(
-                                    splitted_instruction.next().unwrap(),
-                                    splitted_instruction.next().unwrap(),
-                                );
-                            }
-                            "pop" => self.pop_off_memory_segment_of_stack(splitted_instruction),
-                            "add" => self
-                                .convert_double_operand_instruction_to_hack_instruction_set("D+M"),
-                            "sub" => self
-                                .convert_double_operand_instruction_to_hack_instruction_set("M-D"),
-                            "eq" => self.convert_compare_instruction_to_hack_instruction_set("JEQ"),
-                            "lt" => self.convert_compare_instruction_to_hack_instruction_set("JLT"),
-                            "gt" => self.convert_compare_instruction_to_hack_instruction_set("JGT"),
-                            "and" => self
-                                .convert_double_operand_instruction_to_hack_instruction_set("D&M"),
-                            "or" => self
-                                .convert_double_operand_instruction_to_hack_instruction_set("D|M"),
-                            "neg" => self
-                                .convert_single_operand_instruction_to_hack_instruction_set("-M"),
-                            "not" => self
-                                .convert_single_operand_instruction_to_hack_instruction_set("!M"),
-                            "label" => {
-                                self.write_label(splitted_instruction.next().unwrap());
-                            }
-                            "if-goto" => {
-                                let address = splitted_instruction.next().unwrap();
-                                self.load_and_decrement_stack_pointer()
-                                    .assign_value_to_selected_register("A", "M")
-                                    .assign_value_to_selected_register("D", "M")
-                                    .load_address_register(address)
-                                    .write_jump_instruction(None, Some("D"), "JNE");
-                            }
-                            "goto" => {
-                                self.jump_to_address(splitted_instruction.next().unwrap());
-                            }
-                            "call" => self.handle_call_instruction(splitted_instruction),
-                            "function" => self.handle_function_instruction(splitted_instruction),
-                            "return" => self.handle_return_instruction(),
-                            _ => unreachable!(),
-                        };
-                        self.write_hack_instruction_to_file()?;
+    //the value to store is in D, for the big offsets it is parked in a scratch register
+    //while the address is computed
+    fn store_register_d_into_memory_segment(
+        &mut self,
+        memory_segments: &str,
+        ram_address: &str,
+    ) -> &mut Self {
+        match (memory_segments, ram_address.parse::<u16>()) {
+            ("pointer", _) => {
+                let addr = if ram_address == "0" { "THIS" } else { "THAT" };
+                self.load_address_register(addr);
+            }
+            ("static", _) => {
+                self.load_static_in_address_register(self.filename_without_extendion, ram_address);
+            }
+            ("temp", _) => {
+                self.load_temp_address_register(ram_address);
+            }
+            ("ram", _) => {
+                self.load_address_register(ram_address);
+            }
+            (_, Ok(offset)) if offset <= MAX_UNROLLED_POP_OFFSET => {
+                self.load_segment_element_address_into_address_register(memory_segments, offset);
+            }
+            _ => {
+                self.load_address_register(SCRATCH_VALUE)
+                    .set_pointee_value_to_value_in_register_d()
+                    .load_memory_segment_address_into_register_d(memory_segments, ram_address)
+                    .load_address_register(SCRATCH_ADDRESS)
+                    .set_pointee_value_to_value_in_register_d()
+                    .load_address_register(SCRATCH_VALUE)
+                    .set_register_d_to_value_in_pointee()
+                    .load_address_register(SCRATCH_ADDRESS)
+                    .load_pointee_address_into_address_register();
+            }
+        }
+        self.set_pointee_value_to_value_in_register_d()
+    }
+
+    fn pop_into_register_d(&mut self) -> &mut Self {
+        self.load_address_register("SP")
+            .assign_value_to_selected_register("AM", "M-1")
+            .set_register_d_to_value_in_pointee()
+    }
+
+    //dup, swap, drop and over rearrange the values in place, swap without a scratch register
+    //by going through their difference
+    fn write_stack_manipulation(&mut self, instruction: &str) -> &mut Self {
+        match instruction {
+            "dup" => self
+                .load_address_register("SP")
+                .assign_value_to_selected_register("A", "M-1")
+                .assign_value_to_selected_register("D", "M")
+                .assign_value_to_selected_register("A", "A+1")
+                .assign_value_to_selected_register("M", "D")
+                .load_and_increment_stack_pointer(),
+            "swap" => self
+                .load_address_register("SP")
+                .assign_value_to_selected_register("A", "M-1")
+                .assign_value_to_selected_register("D", "M")
+                .assign_value_to_selected_register("A", "A-1")
+                .assign_value_to_selected_register("D", "D-M")
+                .assign_value_to_selected_register("M", "D+M")
+                .assign_value_to_selected_register("A", "A+1")
+                .assign_value_to_selected_register("M", "M-D"),
+            "drop" => self
+                .load_address_register("SP")
+                .assign_value_to_selected_register("M", "M-1"),
+            "over" => self
+                .load_address_register("SP")
+                .assign_value_to_selected_register("A", "M-1")
+                .assign_value_to_selected_register("A", "A-1")
+                .assign_value_to_selected_register("D", "M")
+                .push_register_d_onto_stack(),
+            _ => unreachable!(),
+        }
+    }
+
+    //with the top of the stack in D, dup and over only load D and drop doesn't write anything
+    fn write_cached_stack_manipulation(&mut self, instruction: &str) {
+        match instruction {
+            "dup" if self.top_of_stack_in_register_d => {
+                self.push_register_d_onto_stack();
+            }
+            "dup" => {
+                self.load_address_register("SP")
+                    .assign_value_to_selected_register("A", "M-1")
+                    .assign_value_to_selected_register("D", "M");
+            }
+            "drop" if self.top_of_stack_in_register_d => {}
+            "drop" => {
+                self.load_address_register("SP")
+                    .assign_value_to_selected_register("M", "M-1");
+            }
+            "swap" => {
+                if !self.top_of_stack_in_register_d {
+                    self.pop_into_register_d();
+                }
+                //x + y in RAM, then x into D and y back in RAM
+                self.load_address_register("SP")
+                    .assign_value_to_selected_register("A", "M-1")
+                    .assign_value_to_selected_register("M", "D+M")
+                    .assign_value_to_selected_register("D", "M-D")
+                    .assign_value_to_selected_register("M", "M-D");
+            }
+            "over" => {
+                self.flush_top_of_stack();
+                self.load_address_register("SP")
+                    .assign_value_to_selected_register("A", "M-1")
+                    .assign_value_to_selected_register("A", "A-1")
+                    .assign_value_to_selected_register("D", "M");
+            }
+            _ => unreachable!(),
+        }
+        self.top_of_stack_in_register_d = instruction != "drop";
+    }
+
+    //when caching, the top of the stack lives in D instead of RAM and SP points below it
+    fn flush_top_of_stack(&mut self) {
+        if self.top_of_stack_in_register_d {
+            self.push_register_d_onto_stack();
+            self.top_of_stack_in_register_d = false;
+        }
+    }
+
+    //returns false when the command has no cached form, the caller must then flush the
+    //top of the stack and write the regular template
+    fn write_cached_command(&mut self, command: &Command) -> bool {
+        match command {
+            Command::Push {
+                memory_segment,
+                index,
+            } => {
+                self.flush_top_of_stack();
+                self.load_memory_segment_into_register_d(memory_segment, &index.to_string());
+                self.top_of_stack_in_register_d = true;
+            }
+            Command::Pop {
+                memory_segment,
+                index,
+            } => {
+                if !self.top_of_stack_in_register_d {
+                    self.pop_into_register_d();
+                }
+                self.store_register_d_into_memory_segment(memory_segment, &index.to_string());
+                self.top_of_stack_in_register_d = false;
+            }
+            Command::Arithmetic(instruction)
+                if matches!(instruction.as_str(), "dup" | "swap" | "drop" | "over") =>
+            {
+                self.write_cached_stack_manipulation(instruction);
+            }
+            Command::Arithmetic(instruction) => {
+                let hack_instruction = match instruction.as_str() {
+                    "add" => "D+M",
+                    "sub" => "M-D",
+                    "and" => "D&M",
+                    "or" => "D|M",
+                    "neg" if self.top_of_stack_in_register_d => "-D",
+                    "not" if self.top_of_stack_in_register_d => "!D",
+                    _ => return false,
+                };
+                if hack_instruction.contains('M') {
+                    if !self.top_of_stack_in_register_d {
+                        self.pop_into_register_d();
                     }
+                    self.load_address_register("SP")
+                        .assign_value_to_selected_register("AM", "M-1");
+                }
+                self.assign_value_to_selected_register("D", hack_instruction);
+                self.top_of_stack_in_register_d = true;
+            }
+            Command::IfGoto(address) => {
+                if !self.top_of_stack_in_register_d {
+                    self.pop_into_register_d();
+                }
+                self.load_address_register(address)
+                    .write_jump_instruction(None, Some("D"), "JNE");
+                self.top_of_stack_in_register_d = false;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    //a comparison or `not` right before an `if-goto` jump on D instead of pushing a boolean
+    //and popping it back, returns false when the pair has no fused form
+    fn write_branch_on_condition(&mut self, instruction: &str, address: &str) -> bool {
+        let hack_instruction = match instruction {
+            "not" => {
+                //jumps when x is anything but true (-1)
+                if !self.top_of_stack_in_register_d {
+                    self.pop_into_register_d();
                 }
-                Err(e) => return Err(Error::Io(e)),
+                self.top_of_stack_in_register_d = false;
+                self.assign_value_to_selected_register("D", "D+1")
+                    .load_address_register(address)
+                    .write_jump_instruction(None, Some("D"), "JNE");
+                return true;
+            }
+            instruction => match compare_jump(instruction) {
+                Some(hack_instruction) => hack_instruction,
+                None => return false,
+            },
+        };
+        let overflow_safe = self.options.safe_compare && is_ordering(hack_instruction);
+        if overflow_safe {
+            //the shared routine is smaller than the inlined overflow checks
+            if self.options.optimization == Optimization::Size {
+                return false;
+            }
+            self.flush_top_of_stack();
+            let label_name = self.generate_label();
+            self.write_overflow_safe_difference(&label_name)
+                .load_address_register("SP")
+                .assign_value_to_selected_register("M", "M-1");
+        } else {
+            if !self.top_of_stack_in_register_d {
+                self.pop_into_register_d();
             }
-            line.clear();
+            self.load_address_register("SP")
+                .assign_value_to_selected_register("AM", "M-1")
+                .assign_value_to_selected_register("D", "M-D");
+        }
+        self.top_of_stack_in_register_d = false;
+        self.load_address_register(address).write_jump_instruction(
+            None,
+            Some("D"),
+            hack_instruction,
+        );
+        true
+    }
+
+    //sets the stack up and calls the entry point, only when the program defines it
+    fn write_bootstrap(&mut self, files: &[VmFile]) {
+        let entry = self.options.entry.as_str();
+        let defines_entry = files
+            .iter()
+            .flat_map(|file| &file.commands)
+            .any(|command| matches!(command, Command::Function { name, .. } if name == entry));
+        if defines_entry {
+            self.load_address_register("256")
+                .assign_value_to_selected_register("D", "A")
+                .load_address_register("SP")
+                .assign_value_to_selected_register("M", "D");
+            self.handle_call_instruction(entry, 0);
         }
+    }
 
-        Ok(())
+    //the whole program is buffered so the Hack passes can see across templates
+    fn execution(&mut self, files: &'a [VmFile]) -> Vec<String> {
+        self.write_bootstrap(files);
+        for file in files {
+            self.filename_without_extendion = &file.filename;
+            self.write_commands(&file.commands);
+        }
+
+        self.write_shared_routines();
+        self.hack_instruction.lines().map(str::to_string).collect()
+    }
+
+    fn write_commands(&mut self, commands: &[Command]) {
+        let mut commands = commands.iter().peekable();
+        while let Some(command) = commands.next() {
+            if let (Command::Arithmetic(instruction), Some(Command::IfGoto(address))) =
+                (command, commands.peek())
+            {
+                if self.write_branch_on_condition(instruction, address) {
+                    commands.next();
+                    continue;
+                }
+            }
+            if self.options.cache_top_of_stack && self.write_cached_command(command) {
+                continue;
+            }
+            self.flush_top_of_stack();
+            match command {
+                Command::Push {
+                    memory_segment,
+                    index,
+                } => {
+                    self.push_memory_segment_onto_stack(memory_segment, &index.to_string());
+                }
+                Command::Pop {
+                    memory_segment,
+                    index,
+                } => self.pop_off_memory_segment_of_stack(memory_segment, &index.to_string()),
+                Command::Arithmetic(instruction) => match instruction.as_str() {
+                    "add" => self.convert_double_operand_instruction_to_hack_instruction_set("D+M"),
+                    "sub" => self.convert_double_operand_instruction_to_hack_instruction_set("M-D"),
+                    "eq" | "ne" | "lt" | "lte" | "gt" | "gte" => self
+                        .convert_compare_instruction_to_hack_instruction_set(
+                            compare_jump(instruction).unwrap(),
+                        ),
+                    "and" => self.convert_double_operand_instruction_to_hack_instruction_set("D&M"),
+                    "or" => self.convert_double_operand_instruction_to_hack_instruction_set("D|M"),
+                    "neg" => self.convert_single_operand_instruction_to_hack_instruction_set("-M"),
+                    "not" => self.convert_single_operand_instruction_to_hack_instruction_set("!M"),
+                    "mul" => {
+                        self.call_shared_routine(SharedRoutine::Multiply);
+                    }
+                    "div" => {
+                        self.call_shared_routine(SharedRoutine::Divide);
+                    }
+                    "mod" => {
+                        self.call_shared_routine(SharedRoutine::Divide)
+                            .load_address_register(DIVISION_REMAINDER)
+                            .set_register_d_to_value_in_pointee()
+                            .load_x_address_register()
+                            .set_pointee_value_to_value_in_register_d();
+                    }
+                    "shl" => {
+                        self.call_shared_routine(SharedRoutine::ShiftLeft);
+                    }
+                    "shr" => {
+                        self.call_shared_routine(SharedRoutine::ShiftRight { arithmetic: true });
+                    }
+                    "ushr" => {
+                        self.call_shared_routine(SharedRoutine::ShiftRight { arithmetic: false });
+                    }
+                    "dup" | "swap" | "drop" | "over" => {
+                        self.write_stack_manipulation(instruction);
+                    }
+                    _ => unreachable!(),
+                },
+                Command::Label(label) => {
+                    self.write_label(label);
+                }
+                Command::IfGoto(address) => {
+                    self.load_and_decrement_stack_pointer()
+                        .assign_value_to_selected_register("A", "M")
+                        .assign_value_to_selected_register("D", "M")
+                        .load_address_register(address)
+                        .write_jump_instruction(None, Some("D"), "JNE");
+                }
+                Command::Goto(address) => {
+                    self.jump_to_address(address);
+                }
+                Command::Call {
+                    name,
+                    number_of_args,
+                } if self.options.tail_calls
+                    && !self.current_function_executed.is_empty()
+                    && matches!(commands.peek(), Some(Command::Return)) =>
+                {
+                    commands.next();
+                    self.handle_tail_call_instruction(name, *number_of_args)
+                }
+                Command::Call {
+                    name,
+                    number_of_args,
+                } => self.handle_call_instruction(name, *number_of_args),
+                Command::Function {
+                    name,
+                    number_of_local_variables,
+                } => self.handle_function_instruction(name, *number_of_local_variables),
+                Command::Return => self.handle_return_instruction(),
+                Command::Asm { instructions, .. } => {
+                    for instruction in instructions {
+                        let _ = writeln!(self.hack_instruction, "{}", instruction);
+                    }
+                }
+            };
+        }
+        self.flush_top_of_stack();
     }
 }
 
-impl<'a> Drop for Writer<'a> {
-    fn drop(&mut self) {
-        let _ = std::io::Write::flush(&mut self.writer);
+//jump taken on x - y when x <comparison> y holds
+fn compare_jump(instruction: &str) -> Option<&'static str> {
+    match instruction {
+        "eq" => Some("JEQ"),
+        "ne" => Some("JNE"),
+        "lt" => Some("JLT"),
+        "lte" => Some("JLE"),
+        "gt" => Some("JGT"),
+        "gte" => Some("JGE"),
+        _ => None,
     }
 }
 
+//x - y gives the order of x and y only when it doesn't overflow, unlike their equality
+fn is_ordering(hack_instruction: &str) -> bool {
+    !matches!(hack_instruction, "JEQ" | "JNE")
+}
+
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+        }
     }
 }
 
 impl std::error::Error for Error {}
 
+//a directory `Prog` is translated into `Prog/Prog.asm`, a file `Prog.vm` into `Prog.asm`
 fn open_file(new_file_name: &str) -> Result<File, Error> {
     let mut path = PathBuf::from(new_file_name);
+    if path.is_dir() {
+        //`.` and `..` only get their name once canonicalized
+        path = path.canonicalize().map_err(Error::Io)?;
+        let directory_name = path
+            .file_name()
+            .ok_or_else(|| {
+                Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the root directory has no name to give the .asm file",
+                ))
+            })?
+            .to_os_string();
+        path.push(directory_name);
+    }
     path.set_extension("asm");
     OpenOptions::new()
         .write(true)
@@ -489,44 +1532,575 @@ fn open_file(new_file_name: &str) -> Result<File, Error> {
         .map_err(Error::Io)
 }
 
-pub fn write_hack_instruction_from_jvm_instruction_into_file(
-    reader: BufReader<File>,
-    filename: &str,
+/// Translates the program into Hack instructions, labels included, before the Hack passes.
+pub fn translate(files: &[VmFile], options: &Options) -> Vec<String> {
+    Writer::new(options).execution(files)
+}
+
+pub fn write_hack_instructions_into_file(
+    instructions: &[String],
+    options: &Options,
 ) -> Result<(), Error> {
-    let new_file = open_file(filename)?;
-    let filename_without_extension = Path::new(filename).file_stem().unwrap().to_str().unwrap();
-    let mut stack_write = Writer::new(reader, BufWriter::new(new_file), filename_without_extension);
-    stack_write.execution()?;
-    Ok(())
+    let mut writer = BufWriter::new(open_file(&options.filename)?);
+    for instruction in instructions {
+        writeln!(writer, "{}", instruction).map_err(Error::Io)?;
+    }
+    writer.flush().map_err(Error::Io)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::parser::vm_file;
+    use crate::passes;
+
+    fn size() -> Options {
+        Options {
+            optimization: Optimization::Size,
+            ..Options::default()
+        }
+    }
+
+    fn cached() -> Options {
+        Options {
+            cache_top_of_stack: true,
+            ..Options::default()
+        }
+    }
+
+    fn translate_files(files: &[VmFile], options: &Options) -> Vec<String> {
+        let mut instructions = super::translate(files, options);
+        passes::run_hack_passes(&mut instructions, options);
+        instructions
+    }
+
+    fn translate(program: &str, options: &Options) -> Vec<String> {
+        translate_files(&[vm_file("Test", program)], options)
+    }
+
+    //runs the program from the usual segment pointers, each (address, value) of `ram` written
+    //beforehand
+    fn run_files_with_ram(files: &[VmFile], options: &Options, ram: &[(usize, u16)]) -> Emulator {
+        let mut emulator = Emulator::new(&translate_files(files, options));
+        emulator.ram[..5].copy_from_slice(&[256, 300, 400, 3000, 3010]);
+        for &(address, value) in ram {
+            emulator.ram[address] = value;
+        }
+        emulator.run(1_000_000);
+        emulator
+    }
+
+    fn run_files(files: &[VmFile], options: &Options) -> Emulator {
+        run_files_with_ram(files, options, &[])
+    }
+
+    fn run_with_ram(program: &str, options: &Options, ram: &[(usize, u16)]) -> Emulator {
+        run_files_with_ram(&[vm_file("Test", program)], options, ram)
+    }
+
+    fn run(program: &str, options: &Options) -> Emulator {
+        run_with_ram(program, options, &[])
+    }
+
+    //instruction counts of the table in the module documentation
+    fn count(program: &str) -> usize {
+        passes::count_hack_instructions(&[vm_file("Test", program)], &Options::default())
+    }
 
     #[test]
-    fn test_push_instruction() {}
+    fn test_push_instruction() {
+        for (command, expected) in [
+            ("push constant 1", 6),
+            ("push constant 7", 7),
+            ("push constant -1", 6),
+            ("push constant -2", 7),
+            ("push constant 0x8000", 7),
+            ("push temp 7", 7),
+            ("push pointer 1", 7),
+            ("push static 3", 7),
+            ("push ram KBD", 7),
+            ("push local 1", 8),
+            ("push argument 2", 9),
+            ("push that 9", 10),
+        ] {
+            assert_eq!(count(command), expected, "{}", command);
+        }
+
+        let mut program = String::new();
+        for segment in ["local", "argument"] {
+            for index in 0..12 {
+                program += &format!("push {} {}\npop temp 0\npush temp 0\n", segment, index);
+            }
+        }
+        let ram: Vec<(usize, u16)> = (0..12)
+            .flat_map(|index| {
+                [
+                    (300 + index, 100 + index as u16),
+                    (400 + index, 200 + index as u16),
+                ]
+            })
+            .collect();
+        let emulator = run_with_ram(&program, &Options::default(), &ram);
+        let expected: Vec<u16> = (100..112).chain(200..212).collect();
+        assert_eq!(emulator.ram[256..280], expected);
+    }
+
+    #[test]
+    fn test_pop_instruction() {
+        for (command, expected) in [
+            ("pop temp 7", 5),
+            ("pop pointer 0", 5),
+            ("pop static 3", 5),
+            ("pop local 1", 6),
+            ("pop argument 2", 7),
+            ("pop this 8", 13),
+            ("pop that 9", 14),
+        ] {
+            assert_eq!(count(command), expected, "{}", command);
+        }
+
+        let mut program = String::new();
+        for index in 0..12 {
+            program += &format!(
+                "push constant {}\npop local {}\npush constant {}\npop temp {}\n",
+                100 + index,
+                index,
+                200 + index,
+                index % 8
+            );
+        }
+        for options in [Options::default(), cached()] {
+            let emulator = run(&program, &options);
+            let expected: Vec<u16> = (100..112).collect();
+            assert_eq!(emulator.ram[300..312], expected);
+            assert_eq!(
+                emulator.ram[5..13],
+                [208, 209, 210, 211, 204, 205, 206, 207]
+            );
+            assert_eq!(emulator.ram[0], 256);
+        }
+    }
+
+    const EXTREMES: [i16; 13] = [
+        i16::MIN,
+        i16::MIN + 1,
+        -16384,
+        -2,
+        -1,
+        0,
+        1,
+        2,
+        15,
+        16,
+        16384,
+        i16::MAX - 1,
+        i16::MAX,
+    ];
+
+    fn push_value(value: i16) -> String {
+        if value >= 0 {
+            format!("push constant {}\n", value)
+        } else {
+            format!("push constant {}\nnot\n", !value)
+        }
+    }
+
+    //the result of the comparison as a boolean rebuilt from an `if-goto` on it
+    fn branch_on(instruction: &str, i: usize) -> String {
+        format!(
+            "{}\nif-goto TRUE{}\npush constant 0\ngoto END{}\n\
+             label TRUE{}\npush constant 0\nnot\nlabel END{}",
+            instruction, i, i, i, i
+        )
+    }
+
+    //runs `x <instruction> y` for every pair of extremes, the results are stored from THAT
+    fn evaluate_extremes(
+        instruction: &str,
+        branch: bool,
+        options: &Options,
+    ) -> Vec<(i16, i16, u16)> {
+        let pairs: Vec<(i16, i16)> = EXTREMES
+            .iter()
+            .flat_map(|x| EXTREMES.iter().map(move |y| (*x, *y)))
+            .collect();
+        let program: String = pairs
+            .iter()
+            .enumerate()
+            .map(|(i, (x, y))| {
+                let instruction = if branch {
+                    branch_on(instruction, i)
+                } else {
+                    instruction.to_string()
+                };
+                format!(
+                    "{}{}{}\npop that {}\n",
+                    push_value(*x),
+                    push_value(*y),
+                    instruction,
+                    i
+                )
+            })
+            .collect();
+        let emulator = run(&program, options);
+        assert_eq!(emulator.ram[0], 256);
+        pairs
+            .iter()
+            .enumerate()
+            .map(|(i, (x, y))| (*x, *y, emulator.ram[3010 + i]))
+            .collect()
+    }
+
+    fn assert_compare(instruction: &str, expected: fn(i16, i16) -> bool) {
+        for options in [Options::default(), size(), cached()] {
+            for branch in [false, true] {
+                for (x, y, result) in evaluate_extremes(instruction, branch, &options) {
+                    let expected = if expected(x, y) { u16::MAX } else { 0 };
+                    assert_eq!(result, expected, "{} {} {}", x, instruction, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_eq_instruction() {
+        assert_compare("eq", |x, y| x == y);
+    }
+
+    #[test]
+    fn test_shared_compare_routine() {
+        let program =
+            "push local 0\npush local 1\neq\npush local 2\neq\npush local 3\neq\nlt\n".repeat(3);
+        let program = program.as_str();
+        let speed = translate(program, &Options::default());
+        let size = translate(program, &size());
+        let routine_count = |label: &str| size.iter().filter(|line| *line == label).count();
+        assert_eq!(routine_count("($compare.JEQ)"), 1);
+        assert_eq!(routine_count("($compare.JLT)"), 1);
+        assert_eq!(routine_count("($compare.JGT)"), 0);
+        assert!(size.len() < speed.len());
+    }
+
+    #[test]
+    fn test_lt_instruction() {
+        assert_compare("lt", |x, y| x < y);
+    }
+
+    #[test]
+    fn test_gt_instruction() {
+        assert_compare("gt", |x, y| x > y);
+    }
+
+    #[test]
+    fn test_unsafe_compare_overflows() {
+        let options = Options {
+            safe_compare: false,
+            ..Options::default()
+        };
+        for branch in [false, true] {
+            let results = evaluate_extremes("lt", branch, &options);
+            assert!(results.contains(&(i16::MAX, -2, u16::MAX)));
+        }
+        assert!(
+            translate("lt\ngt", &options).len() < translate("lt\ngt", &Options::default()).len()
+        );
+    }
+
+    #[test]
+    fn test_fused_compare_and_branch() {
+        let program = "label LOOP\npush local 0\npush constant 10\nlt\nif-goto LOOP\n\
+                       push local 1\nnot\nif-goto LOOP";
+        let unsafe_compare = Options {
+            safe_compare: false,
+            ..Options::default()
+        };
+        //the loop header is the compare and the jump on D, with no boolean in between
+        let fused = translate(program, &unsafe_compare);
+        assert!(!fused.iter().any(|line| line.starts_with("(LABEL.")));
+        let unfused = program.replace("\nif-goto", "\nlabel SPLIT\nif-goto");
+        for options in [Options::default(), unsafe_compare] {
+            assert!(translate(program, &options).len() < translate(&unfused, &options).len());
+        }
+        for options in [Options::default(), size(), cached()] {
+            for (local, jumps) in [(0u16, true), (u16::MAX, false), (1, true)] {
+                let program = "push local 0\nnot\nif-goto TAKEN\npush constant 1\npop temp 0\n\
+                               label TAKEN\nlabel END\ngoto END";
+                let emulator = run_with_ram(program, &options, &[(300, local)]);
+                assert_eq!(emulator.ram[5] == 0, jumps, "not {}", local);
+                assert_eq!(emulator.ram[0], 256);
+            }
+        }
+    }
+
+    fn assert_arithmetic(instruction: &str, expected: fn(i16, i16) -> i16) {
+        for options in [Options::default(), size(), cached()] {
+            for (x, y, result) in evaluate_extremes(instruction, false, &options) {
+                assert_eq!(result as i16, expected(x, y), "{} {} {}", x, instruction, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mul_instruction() {
+        assert_arithmetic("mul", |x, y| x.wrapping_mul(y));
+    }
 
     #[test]
-    fn test_pop_instruction() {}
+    fn test_div_and_mod_instructions() {
+        assert_arithmetic("div", |x, y| if y == 0 { 0 } else { x.wrapping_div(y) });
+        assert_arithmetic("mod", |x, y| if y == 0 { x } else { x.wrapping_rem(y) });
+    }
 
     #[test]
-    fn test_eq_instruction() {}
+    fn test_shift_instructions() {
+        assert_arithmetic("shl", |x, y| {
+            (x as u16).checked_shl(y as u16 as u32).unwrap_or(0) as i16
+        });
+        assert_arithmetic("shr", |x, y| x >> (y as u16).min(15));
+        assert_arithmetic("ushr", |x, y| {
+            (x as u16).checked_shr(y as u16 as u32).unwrap_or(0) as i16
+        });
+    }
 
     #[test]
-    fn test_lt_instruction() {}
+    fn test_arithmetic_routines_are_emitted_when_used() {
+        let lines = translate(
+            "push local 0\npush local 1\nmod\nmul\nmul",
+            &Options::default(),
+        );
+        let count = |label: &str| lines.iter().filter(|line| *line == label).count();
+        assert_eq!(count("($mul)"), 1);
+        assert_eq!(count("($divide)"), 1);
+        assert_eq!(count("($shl)") + count("($shr)") + count("($ushr)"), 0);
+        let lines = translate("push local 0\npush local 1\nadd", &Options::default());
+        assert!(!lines.iter().any(|line| line.starts_with("($")));
+    }
 
     #[test]
-    fn test_gt_instruction() {}
+    fn test_gte_instruction() {
+        assert_compare("gte", |x, y| x >= y);
+    }
 
     #[test]
-    fn test_gte_instruction() {}
+    fn test_lte_instruction() {
+        assert_compare("lte", |x, y| x <= y);
+    }
 
     #[test]
-    fn test_lte_instruction() {}
+    fn test_ne_instruction() {
+        assert_compare("ne", |x, y| x != y);
+    }
 
     #[test]
     fn test_and_instruction() {}
 
     #[test]
     fn test_or_instruction() {}
+
+    #[test]
+    fn test_call_and_return() {
+        let program = "push constant 3\npush constant 4\ncall Main.add 2\npop temp 0\n\
+                       label END\ngoto END\n\
+                       function Main.add 1\npush argument 0\npush argument 1\nadd\n\
+                       push constant 1000\npop this 0\nreturn";
+        for options in [&Options::default(), &size(), &cached()] {
+            let emulator = run(program, options);
+            assert_eq!(emulator.ram[5], 7);
+            assert_eq!(emulator.ram[..5], [256, 300, 400, 3000, 3010]);
+            assert_eq!(emulator.ram[3000], 1000);
+        }
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        //Main.sum recurses 2000 calls deep, Main.spread tail calls a function taking more
+        //arguments than it got itself and Main.add3 one taking fewer
+        let program = "push constant 2000\npush constant 0\ncall Main.sum 2\npop temp 0\n\
+                       push constant 5\ncall Main.spread 1\npop temp 1\nlabel END\ngoto END\n\
+                       function Main.sum 0\npush argument 0\nif-goto REC\npush argument 1\nreturn\n\
+                       label REC\npush argument 0\npush constant 1\nsub\n\
+                       push argument 1\npush argument 0\nadd\ncall Main.sum 2\nreturn\n\
+                       function Main.spread 1\npush constant 1000\npop pointer 1\npush argument 0\n\
+                       push constant 10\npush constant 20\ncall Main.add3 3\nreturn\n\
+                       function Main.add3 2\npush argument 0\npush argument 1\nadd\n\
+                       push argument 2\nadd\ncall Main.first 1\nreturn\n\
+                       function Main.first 0\npush argument 0\nreturn";
+        let no_tail_calls = Options {
+            tail_calls: false,
+            ..Options::default()
+        };
+        for options in [&Options::default(), &size(), &cached(), &no_tail_calls] {
+            let emulator = run(program, options);
+            assert_eq!(emulator.ram[5..7], [(1..=2000u32).sum::<u32>() as u16, 35]);
+            assert_eq!(emulator.ram[..5], [256, 300, 400, 3000, 3010]);
+            let stack_is_bounded = emulator.ram[600..2000].iter().all(|&word| word == 0);
+            assert_eq!(stack_is_bounded, options.tail_calls);
+        }
+    }
+
+    #[test]
+    fn test_prologue_zeroes_locals() {
+        for options in [Options::default(), size()] {
+            for locals in [0, 1, 3, 4, 8, 9, 20] {
+                let program = format!(
+                    "call Main.f 0\npop temp 0\nlabel END\ngoto END\n\
+                     function Main.f {}\npush constant 1\npush constant 2\npop temp 1\nreturn",
+                    locals
+                );
+                let lines = translate(&program, &options);
+                assert!(!lines.iter().any(|line| line.contains("LOOP")));
+                let stack: Vec<(usize, u16)> =
+                    (256..300).map(|address| (address, 0xBEEF)).collect();
+                let emulator = run_with_ram(&program, &options, &stack);
+                //the frame is 5 words above the caller's stack, then come the locals
+                assert_eq!(emulator.ram[261..261 + locals], vec![0; locals]);
+                assert_eq!(emulator.ram[261 + locals], 1);
+                assert_eq!(emulator.ram[5..7], [1, 2]);
+                assert_eq!(emulator.ram[..5], [256, 300, 400, 3000, 3010]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_constants_outside_of_a_instruction_range() {
+        let program: String = EXTREMES
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("push constant {}\npop static {}\n", value, i))
+            .collect();
+        for options in [Options::default(), cached()] {
+            let emulator = run(&program, &options);
+            let values: Vec<i16> = emulator.ram[16..16 + EXTREMES.len()]
+                .iter()
+                .map(|value| *value as i16)
+                .collect();
+            assert_eq!(values, EXTREMES);
+        }
+    }
+
+    #[test]
+    fn test_ram_segment() {
+        let program = "push constant 3\npop pointer 1\npush ram KBD\npop ram SCREEN+32\n\
+                       push ram R1\npop temp 0";
+        for options in [Options::default(), cached()] {
+            let emulator = run_with_ram(program, &options, &[(24576, 75)]);
+            assert_eq!(emulator.ram[16384 + 32], 75);
+            assert_eq!(emulator.ram[5], 300);
+            //THAT isn't needed to reach the memory mapped I/O
+            assert_eq!(emulator.ram[4], 3);
+        }
+    }
+
+    #[test]
+    fn test_stack_manipulation() {
+        let program = "push constant 1\npush constant 2\nswap\nover\ndup\nadd\ndrop\n\
+                       pop temp 0\npop temp 1\npush constant 5\ndup\ndrop\npop temp 2\n\
+                       push constant -32768\npush constant 32767\nswap\npop temp 3\npop temp 4\n\
+                       push constant 7\nneg\npush constant 3\nover\nsub\nswap\ndrop\npop temp 5";
+        for options in [Options::default(), size(), cached()] {
+            let emulator = run(program, &options);
+            assert_eq!(emulator.ram[5..11], [1, 2, 5, 0x8000, 32767, 10]);
+            assert_eq!(emulator.ram[0], 256);
+        }
+    }
+
+    #[test]
+    fn test_asm_blocks() {
+        let program = "function Sys.init 0\npush constant 5\nneg\ncall Main.abs 1\n\
+                       push constant 3\ncall Main.abs 1\nadd\npop temp 0\nlabel END\ngoto END\n\
+                       function Main.abs 0\npush argument 0\n\
+                       asm 1 1 // leaves the absolute value on the stack\n\
+                       @SP\nA=M-1\nD=M\n@ABS_POSITIVE\nD;JGE // a label of its own\n\
+                       @SP\nA=M-1\nM=-M\n(ABS_POSITIVE)\nendasm\nreturn";
+        for options in [Options::default(), size(), cached()] {
+            let lines = translate(program, &options);
+            let position = |instruction: &str| lines.iter().position(|line| line == instruction);
+            assert!(position("@ABS_POSITIVE") < position("(ABS_POSITIVE)"));
+            //copied as it is, right where the block is
+            assert_eq!(
+                lines
+                    .iter()
+                    .filter(|line| *line == "(ABS_POSITIVE)")
+                    .count(),
+                1
+            );
+            assert_eq!(run(program, &options).ram[5], 8);
+        }
+    }
+
+    #[test]
+    fn test_shared_call_and_return_are_smaller() {
+        let program = format!(
+            "function Main.main 0\n{}return\n",
+            "call Main.main 0\n".repeat(3)
+        );
+        assert!(
+            translate(&program, &size()).len() < translate(&program, &Options::default()).len()
+        );
+    }
+
+    #[test]
+    fn test_bootstrap_calls_entry() {
+        let program = "function Main.f 0\npush constant 42\nreturn\n\
+                       function Sys.init 0\ncall Main.f 0\npop temp 0\nlabel END\ngoto END";
+        let emulator = run(program, &Options::default());
+        assert_eq!(emulator.ram[5], 42);
+        assert_eq!(emulator.ram[0], 261);
+    }
+
+    #[test]
+    fn test_inlined_calls() {
+        let program = "push constant 3\npush constant 4\ncall Main.sub 2\npop temp 0\n\
+                       push constant 1000\npop pointer 0\n\
+                       push constant 9\ncall Main.getThis 1\npop temp 1\n\
+                       label END\ngoto END\n\
+                       function Main.sub 1\npush argument 0\npush argument 1\nsub\n\
+                       pop local 0\npush local 0\nreturn\n\
+                       function Main.getThis 0\npush argument 0\npop pointer 0\n\
+                       push pointer 0\nreturn";
+        let mut files = [vm_file("Test", program)];
+        assert_eq!(crate::inlining::inline(&mut files, &Options::default()), 2);
+        let emulator = run_files(&files, &Options::default());
+        assert_eq!(emulator.ram[5..7], [(-1i16) as u16, 9]);
+        assert_eq!(emulator.ram[..5], [256, 300, 400, 1000, 3010]);
+    }
+
+    #[test]
+    fn test_cached_top_of_stack() {
+        //sums 10 + 9 + ... + 1 into local 0 then mixes every segment and operator
+        let program = "push constant 10\npop argument 0\n\
+                       label LOOP\npush local 0\npush argument 0\nadd\npop local 0\n\
+                       push argument 0\npush constant 1\nsub\npop argument 0\n\
+                       push argument 0\nif-goto LOOP\n\
+                       push constant 3030\npop pointer 0\npush local 0\nneg\npop this 2\n\
+                       push this 2\nnot\npush constant 12\nand\npop temp 3\n\
+                       push temp 3\npush constant 3\nor\npop static 1\n\
+                       push static 1\npush local 0\npush temp 3\nsub\neq\nneg";
+        let expected = run(program, &Options::default());
+        let emulator = run(program, &cached());
+        assert_eq!(emulator.ram[300], 55);
+        //scratch registers and the dead slots above the stack pointer may differ
+        for address in [0, 1, 2, 3, 4, 8, 16, 256, 300, 400, 3032] {
+            assert_eq!(
+                emulator.ram[address], expected.ram[address],
+                "RAM[{}]",
+                address
+            );
+        }
+        assert!(
+            translate(program, &cached()).len() < translate(program, &Options::default()).len()
+        );
+    }
+
+    #[test]
+    fn test_output_file_of_a_directory() {
+        let directory =
+            std::env::temp_dir().join(format!("vm_translator_output_{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("Prog/sub")).unwrap();
+        let relative = directory.join("Prog/sub/..");
+        open_file(&relative.display().to_string()).unwrap();
+        assert!(directory.join("Prog/Prog.asm").is_file());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
 }